@@ -1,17 +1,54 @@
-use std::fmt;
-use crate::value::Value;
+use std::collections::HashMap;
 
-#[derive(Clone)]
+use crate::diagnostic::Span;
+use crate::object::ObjRef;
+use crate::value::{Value, ValueType};
+
+// every instruction is a single opcode byte followed by its operands
+// constant operands are an index into Chunk::constants, one byte wide,
+// or three bytes wide (little endian) for the *Long variants
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum OpCode {
-    Constant(Value),
-    DefineGlobal(String),
-    GetGlobal(String),
-    SetGlobal(String),
-    SetLocal(usize),
-    GetLocal(usize),
-    JumpIfFalse(usize),
-    Jump(usize),
-    Loop(usize),
+    Constant,
+    ConstantLong,
+    DefineGlobal,
+    DefineGlobalLong,
+    GetGlobal,
+    GetGlobalLong,
+    SetGlobal,
+    SetGlobalLong,
+    // one byte stack slot, relative to the frame
+    SetLocal,
+    GetLocal,
+    // two byte forward or backward distance
+    JumpIfFalse,
+    Jump,
+    Loop,
+    // one byte argument count
+    Call,
+    // followed by an (is_local, index) byte pair per captured upvalue
+    Closure,
+    ClosureLong,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Class,
+    ClassLong,
+    Method,
+    MethodLong,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    // name constant then a one byte argument count
+    Invoke,
+    InvokeLong,
+    Inherit,
+    GetSuper,
+    GetSuperLong,
+    SuperInvoke,
+    SuperInvokeLong,
     True,
     False,
     Pop,
@@ -27,66 +64,246 @@ pub enum OpCode {
     Equal,
     Greater,
     Less,
+    // one byte element count, the elements are on the stack in order
+    BuildList,
+    // one byte entry count, each entry is a key then a value on the stack
+    BuildMap,
+    GetIndex,
+    SetIndex,
+    // turns the value on top of the stack into the string `print` would show, minus any quotes
+    Stringify,
+    Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    // one byte depth, pushes a copy of the value that many slots below the top, zero being the top
+    Dup,
+    // one byte depth, moves the top value down under that many others
+    Bury,
 }
 
-impl fmt::Display for OpCode {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl OpCode {
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        let op = match byte {
+            0 => OpCode::Constant,
+            1 => OpCode::ConstantLong,
+            2 => OpCode::DefineGlobal,
+            3 => OpCode::DefineGlobalLong,
+            4 => OpCode::GetGlobal,
+            5 => OpCode::GetGlobalLong,
+            6 => OpCode::SetGlobal,
+            7 => OpCode::SetGlobalLong,
+            8 => OpCode::SetLocal,
+            9 => OpCode::GetLocal,
+            10 => OpCode::JumpIfFalse,
+            11 => OpCode::Jump,
+            12 => OpCode::Loop,
+            13 => OpCode::Call,
+            14 => OpCode::Closure,
+            15 => OpCode::ClosureLong,
+            16 => OpCode::GetUpvalue,
+            17 => OpCode::SetUpvalue,
+            18 => OpCode::CloseUpvalue,
+            19 => OpCode::Class,
+            20 => OpCode::ClassLong,
+            21 => OpCode::Method,
+            22 => OpCode::MethodLong,
+            23 => OpCode::GetProperty,
+            24 => OpCode::GetPropertyLong,
+            25 => OpCode::SetProperty,
+            26 => OpCode::SetPropertyLong,
+            27 => OpCode::Invoke,
+            28 => OpCode::InvokeLong,
+            29 => OpCode::Inherit,
+            30 => OpCode::GetSuper,
+            31 => OpCode::GetSuperLong,
+            32 => OpCode::SuperInvoke,
+            33 => OpCode::SuperInvokeLong,
+            34 => OpCode::True,
+            35 => OpCode::False,
+            36 => OpCode::Pop,
+            37 => OpCode::Nil,
+            38 => OpCode::Add,
+            39 => OpCode::Subtract,
+            40 => OpCode::Multiply,
+            41 => OpCode::Divide,
+            42 => OpCode::Return,
+            43 => OpCode::Not,
+            44 => OpCode::Negate,
+            45 => OpCode::Print,
+            46 => OpCode::Equal,
+            47 => OpCode::Greater,
+            48 => OpCode::Less,
+            49 => OpCode::BuildList,
+            50 => OpCode::BuildMap,
+            51 => OpCode::GetIndex,
+            52 => OpCode::SetIndex,
+            53 => OpCode::Stringify,
+            54 => OpCode::Modulo,
+            55 => OpCode::Power,
+            56 => OpCode::BitAnd,
+            57 => OpCode::BitOr,
+            58 => OpCode::BitXor,
+            59 => OpCode::BitNot,
+            60 => OpCode::ShiftLeft,
+            61 => OpCode::ShiftRight,
+            62 => OpCode::Dup,
+            63 => OpCode::Bury,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    // the variant taking a three byte constant index, for ops that have one
+    pub fn long_form(self) -> Option<OpCode> {
         match self {
-            OpCode::Constant(val) => write!(f, "Constant {}", val),
-            OpCode::DefineGlobal(val) => write!(f, "DefineGlobal {}", val),
-            OpCode::GetGlobal(val) => write!(f, "GetGlobal {}", val),
-            OpCode::SetGlobal(val) => write!(f, "SetGlobal {}", val),
-            OpCode::GetLocal(val) => write!(f, "GetLocal {}", val),
-            OpCode::SetLocal(val) => write!(f, "SetLocal {}", val),
-            OpCode::JumpIfFalse(val) => write!(f, "JumpIfFalse: {}", val),
-            OpCode::Jump(val) => write!(f, "Jump: {}", val),
-            OpCode::Loop(val) => write!(f, "Loop: {}", val),
-            OpCode::True => write!(f, "OpCode::True"),
-            OpCode::False => write!(f, "OpCode::False"),
-            OpCode::Pop => write!(f, "OpCode::Pop"),
-            OpCode::Nil => write!(f, "OpCode::Nil"),
-            OpCode::Add => write!(f, "OpCode::Add"),
-            OpCode::Subtract => write!(f, "OpCode::Subtract"),
-            OpCode::Multiply => write!(f, "OpCode::Multiply"),
-            OpCode::Divide => write!(f, "OpCode::Divide"),
-            OpCode::Return => write!(f, "OpCode::Return"),
-            OpCode::Negate => write!(f, "OpCode::Negate"),
-            OpCode::Not => write!(f, "OpCode::Not"),
-            OpCode::Equal => write!(f, "OpCode::Equal"),
-            OpCode::Less => write!(f, "OpCode::Less"),
-            OpCode::Greater => write!(f, "OpCode::Greater"),
-            OpCode::Print => write!(f, "Print"),
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::Invoke => Some(OpCode::InvokeLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            OpCode::SuperInvoke => Some(OpCode::SuperInvokeLong),
+            _ => None,
         }
     }
+
+    pub fn is_long(self) -> bool {
+        matches!(self, OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClosureLong
+                | OpCode::ClassLong
+                | OpCode::MethodLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::InvokeLong
+                | OpCode::GetSuperLong
+                | OpCode::SuperInvokeLong)
+    }
 }
 
-#[derive(Clone)]
-pub struct CodeLine {
-    pub code: OpCode,
+// the biggest constant index a long operand can hold
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+// the token an instruction was compiled from, so errors can point back at it
+#[derive(Clone, Copy, PartialEq)]
+pub struct Location {
     pub line: i32,
+    pub column: usize,
+    pub span: Span,
 }
 
-impl fmt::Display for CodeLine {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{{ Code: {}, Line: {} }}", self.code, self.line)
+// a stretch of `count` consecutive code bytes that all came from `location`
+#[derive(Clone, Copy)]
+pub struct LocationRun {
+    pub location: Location,
+    pub count: usize,
+}
+
+// what makes two constants interchangeable, so add_constant can find an entry without a scan
+// floats go by their bits, keeping 0 and -0 apart, and ints never share with floats
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Number(u64),
+    // interned, so the handle stands for the contents
+    String(ObjRef),
+}
+
+impl ConstantKey {
+    // None for functions, every one of them is its own constant
+    fn of(value: &Value) -> Option<ConstantKey> {
+        match value.value_type {
+            ValueType::Nil => Some(ConstantKey::Nil),
+            ValueType::Bool(b) => Some(ConstantKey::Bool(b)),
+            ValueType::Int(int) => Some(ConstantKey::Int(int)),
+            ValueType::Number(number) => Some(ConstantKey::Number(number.to_bits())),
+            ValueType::String(handle) => Some(ConstantKey::String(handle)),
+            _ => None,
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Chunk {
-    pub code: Vec<CodeLine>,
+    pub code: Vec<u8>,
     pub constants: Vec<Value>,
+    pub locations: Vec<LocationRun>,
+    // where each constant added through add_constant sits in `constants`
+    constant_indices: HashMap<ConstantKey, usize>,
 }
 
 impl Chunk {
     pub fn new_chunk() -> Chunk {
         Chunk {
-            code: Vec::new(), 
-            constants: Vec::new(), 
+            code: Vec::new(),
+            constants: Vec::new(),
+            locations: Vec::new(),
+            constant_indices: HashMap::new(),
+        }
+    }
+
+    pub fn write(&mut self, byte: u8, location: Location) -> () {
+        self.code.push(byte);
+
+        match self.locations.last_mut() {
+            Some(run) if run.location == location => run.count += 1,
+            _ => self.locations.push(LocationRun { location, count: 1 }),
+        }
+    }
+
+    // adds a value to the constant pool and returns its index
+    // equal values share a slot, which mostly saves space on repeated identifiers
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::of(&value);
+        if let Some(index) = key.and_then(|key| self.constant_indices.get(&key)) {
+            return *index;
+        }
+
+        let index = self.constants.len();
+        self.constants.push(value);
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+        index
+    }
+
+    pub fn get_location(&self, offset: usize) -> Location {
+        let mut end = 0;
+        for run in self.locations.iter() {
+            end += run.count;
+            if offset < end {
+                return run.location;
+            }
         }
+
+        panic!("Chunk::get_location called with offset {} past the end of the chunk", offset)
+    }
+
+    pub fn get_line(&self, offset: usize) -> i32 {
+        self.get_location(offset).line
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        (self.code[offset] as u16) | ((self.code[offset + 1] as u16) << 8)
     }
-    
-    pub fn write(&mut self, code: OpCode, line: i32) -> () {
-        self.code.push(CodeLine {code, line})
+
+    pub fn read_u24(&self, offset: usize) -> usize {
+        (self.code[offset] as usize)
+            | ((self.code[offset + 1] as usize) << 8)
+            | ((self.code[offset + 2] as usize) << 16)
     }
 }
@@ -1,5 +1,8 @@
-use crate::scanner::{Scanner, TokenType, Token};
-use crate::chunk::{Chunk, OpCode};
+use crate::scanner::{self, Scanner, TokenType, Token};
+use crate::chunk::{Chunk, Location, OpCode, MAX_CONSTANTS};
+use crate::diagnostic::{Diagnostic, ErrorCode};
+use crate::memory::Heap;
+use crate::object::{Function, Obj, ObjRef};
 use crate::value::Value;
 
 struct Parser {
@@ -15,14 +18,18 @@ impl Parser {
             current: Token { 
                 token_type: TokenType::_Default,
                 line: 0,
+                column: 0,
                 lexeme: "".to_string(),
                 start: 0,
+                length: 0,
             },
             previous: Token {
                 token_type: TokenType:: _Default,
                 line: 0,
+                column: 0,
                 lexeme: "".to_string(),
                 start: 0,
+                length: 0,
             },
             had_error: false,
             panic_mode: false,
@@ -38,9 +45,15 @@ enum Precedence {
     And,
     Equality,
     Comparison,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
+    // binds tighter than a unary minus on its left, so -2 ** 2 is -4
+    Exponent,
     Call,
     Primary,
 }
@@ -53,10 +66,15 @@ impl Precedence {
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
-            Precedence::Comparison => Precedence::Term,
+            Precedence::Comparison => Precedence::BitOr,
+            Precedence::BitOr => Precedence::BitXor,
+            Precedence::BitXor => Precedence::BitAnd,
+            Precedence::BitAnd => Precedence::Shift,
+            Precedence::Shift => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
-            Precedence::Unary => Precedence::Call,
+            Precedence::Unary => Precedence::Exponent,
+            Precedence::Exponent => Precedence::Call,
             Precedence::Call => Precedence::Primary,
             Precedence::Primary => panic!("No matching rule higher than Primary"),
         }
@@ -68,30 +86,134 @@ struct Local {
     name: String,
     depth: usize,
     initialized: bool,
+    // set when a closure closes over this local, so end_scope knows to hoist it
+    is_captured: bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// a variable a closure captures, either straight from the enclosing function's locals
+// or from an upvalue the enclosing function captured itself
+#[derive(Clone, Copy)]
+struct UpvalueCapture {
+    is_local: bool,
+    index: usize,
+}
+
+// the loop a `break` or `continue` belongs to
+struct LoopContext {
+    // where `continue` jumps back to, the increment clause for `for` loops
+    continue_target: usize,
+    // locals deeper than this belong to the body and are popped when leaving it early
+    scope_depth: usize,
+    // `break` jumps, patched to the end of the loop once it's been compiled
+    break_jumps: Vec<usize>,
+}
+
+// one of these exists for every function body we're in the middle of compiling
+// the innermost function is always the last one in Compiler::functions
+struct FunctionCompiler {
+    function: Function,
+    function_type: FunctionType,
+
+    scope_depth: usize,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueCapture>,
+    // innermost last; per function, so a loop doesn't reach into functions declared inside it
+    loops: Vec<LoopContext>,
+}
+
+impl FunctionCompiler {
+    fn new(function_type: FunctionType, name: Option<ObjRef>, file: ObjRef) -> FunctionCompiler {
+        // slot zero is claimed by the VM for the function being called
+        // in methods it holds the receiver, so it's reachable as `this`
+        // otherwise give it an empty name so user code can never resolve it
+        let reserved_name = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        let reserved = Local {
+            name: reserved_name.to_string(),
+            depth: 0,
+            initialized: true,
+            is_captured: false,
+        };
+
+        FunctionCompiler {
+            function: Function::new(name, file),
+            function_type,
+            scope_depth: 0,
+            locals: vec![reserved],
+            upvalues: Vec::new(),
+            loops: Vec::new(),
+        }
+    }
+}
+
+// a field or element being rewritten in place, by `+=` style assignment or `++`/`--`
+#[derive(Clone, Copy)]
+enum Target {
+    // the instance is on the stack, the operand is the name constant
+    Field(usize, Location),
+    // the collection and the index are on the stack, errors point at the bracket
+    Index(Location),
+}
+
+#[derive(Clone, Copy)]
+enum Update {
+    // `target op= value`, the value's expression is still to be parsed
+    Compound(OpCode),
+    // `++target`, evaluates to the new value
+    Prefix(OpCode),
+    // `target++`, evaluates to the old value
+    Postfix(OpCode),
+}
+
+// tracks the class bodies we're nested in, so `this` and `super` know whether they're legal
+struct ClassCompiler {
+    has_superclass: bool,
 }
 
 pub struct Compiler<'a> {
+    // strings and finished functions are allocated straight onto the VM heap
+    // the collector never runs during compilation, so nothing here needs rooting
+    heap: &'a mut Heap,
     scanner: Scanner,
     parser: Parser,
-    compiling_chunk: &'a mut Chunk,
-    
-    scope_depth: usize,
-    locals: Vec<Local>,
+    functions: Vec<FunctionCompiler>,
+    classes: Vec<ClassCompiler>,
+    diagnostics: Vec<Diagnostic>,
+    file: ObjRef,
 }
 
 impl Compiler<'_> {
-    pub fn new(chunk: &mut Chunk) -> Compiler {
+    pub fn new(heap: &mut Heap) -> Compiler<'_> {
+        let file = heap.intern("");
         Compiler {
-            scanner: Scanner::new(&"".to_string()),
+            heap,
+            scanner: Scanner::new(""),
             parser: Parser::new(),
-            compiling_chunk: chunk,
-            locals: Vec::new(),
-            scope_depth: 0,
+            functions: Vec::new(),
+            classes: Vec::new(),
+            diagnostics: Vec::new(),
+            file,
         }
     }
 
-    pub fn compile(&mut self,source: &String) -> bool {
-        self.scanner = Scanner::new(&source);
+    // on failure every error found is returned, not just the first
+    // `file` names the source in stack traces, it doesn't have to be a real path
+    pub fn compile(&mut self, source: &str, file: &str) -> Result<ObjRef, Vec<Diagnostic>> {
+        self.scanner = Scanner::new(source);
+        self.file = self.heap.intern(file);
+        self.functions = vec![FunctionCompiler::new(FunctionType::Script, None, self.file)];
+        self.classes = Vec::new();
+        self.diagnostics = Vec::new();
         self.reset_error_state();
         self.advance();
         
@@ -99,12 +221,32 @@ impl Compiler<'_> {
             self.declaration();
         }
         
-        self.end_compiler();
-        !self.parser.had_error
+        let (function, _) = self.end_compiler();
+        if self.parser.had_error {
+            Err(std::mem::take(&mut self.diagnostics))
+        } else {
+            Ok(self.heap.alloc(Obj::Function(function)))
+        }
+    }
+
+    fn current(&self) -> &FunctionCompiler {
+        self.functions.last().unwrap()
+    }
+
+    fn current_mut(&mut self) -> &mut FunctionCompiler {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.current_mut().function.chunk
     }
 
     fn declaration(&mut self) -> () {
-        if self.match_token(TokenType::Var) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
             self.var_declaration();
         } else {
             self.statement();
@@ -131,8 +273,18 @@ impl Compiler<'_> {
             self.for_statement();
         } else if self.match_token(TokenType::If) {
            self.if_statement(); 
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
         } else if self.match_token(TokenType::While) {
             self.while_statement();    
+        } else if self.match_token(TokenType::Switch) {
+            self.switch_statement();
+        } else if self.check(TokenType::LeftBrace) && self.starts_map_literal() {
+            self.expression_statement();
         } else if self.match_token(TokenType::LeftBrace) {
             // open brace means new block scope
             self.begin_scope();
@@ -143,25 +295,111 @@ impl Compiler<'_> {
         }
     }
 
+    // a statement starting with `{` is a block, unless it's `{key: ...`
+    // nothing in a block can start with a token followed by a colon
+    fn starts_map_literal(&mut self) -> bool {
+        let ahead = self.scanner.peek_tokens(2);
+        ahead[1].token_type == TokenType::Colon
+    }
+
     fn begin_scope(&mut self) -> () {
-        self.scope_depth += 1;
+        self.current_mut().scope_depth += 1;
     }
 
     fn end_scope(&mut self) -> () {
-        self.scope_depth -= 1;
+        self.current_mut().scope_depth -= 1;
 
         // pop locals vector until we get rid of all of the old scope values
         // that is, anything with a value greater than current scope depth needs to die
-        while self.locals.len() > 0 && self.locals.last().unwrap().depth > self.scope_depth {
-            self.locals.pop();
-            self.emit_byte(OpCode::Pop);
+        while !self.current().locals.is_empty() && self.current().locals.last().unwrap().depth > self.current().scope_depth {
+            // captured locals need to move off the stack before their slot goes away
+            if self.current().locals.last().unwrap().is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+            self.current_mut().locals.pop();
+        }
+    }
+
+    // pops the locals deeper than `depth` off the VM stack without forgetting them,
+    // for jumping out of scopes the compiler is still inside of
+    fn discard_locals(&mut self, depth: usize) -> () {
+        let captured: Vec<bool> = self.current().locals.iter().rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| local.is_captured)
+            .collect();
+
+        for is_captured in captured {
+            if is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+        }
+    }
+
+    fn begin_loop(&mut self, continue_target: usize) -> () {
+        let scope_depth = self.current().scope_depth;
+        self.current_mut().loops.push(LoopContext { continue_target, scope_depth, break_jumps: Vec::new() });
+    }
+
+    // sends every `break` in the loop to the current end of the code
+    fn end_loop(&mut self) -> () {
+        let context = self.current_mut().loops.pop().unwrap();
+        for jump in context.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) -> () {
+        if self.current().loops.is_empty() {
+            self.error(ErrorCode::InvalidLoopControl, "Cannot use 'break' outside of a loop.");
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+
+        if let Some(depth) = self.current().loops.last().map(|context| context.scope_depth) {
+            self.discard_locals(depth);
+            let jump = self.emit_jump(OpCode::Jump);
+            self.current_mut().loops.last_mut().unwrap().break_jumps.push(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) -> () {
+        if self.current().loops.is_empty() {
+            self.error(ErrorCode::InvalidLoopControl, "Cannot use 'continue' outside of a loop.");
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+
+        if let Some(context) = self.current().loops.last() {
+            let (depth, target) = (context.scope_depth, context.continue_target);
+            self.discard_locals(depth);
+            self.emit_loop(target);
         }
     }
 
     fn print_statement(&mut self) -> () {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_byte(OpCode::Print);
+        self.emit_op(OpCode::Print);
+    }
+
+    fn return_statement(&mut self) -> () {
+        if self.current().function_type == FunctionType::Script {
+            self.error(ErrorCode::InvalidReturn, "Cannot return from top-level code.");
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current().function_type == FunctionType::Initializer {
+                self.error(ErrorCode::InvalidReturn, "Cannot return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_op(OpCode::Return);
+        }
     }
 
     fn for_statement(&mut self) -> () {
@@ -178,7 +416,7 @@ impl Compiler<'_> {
             self.expression_statement();
         }
 
-        let mut loop_start = self.compiling_chunk.code.len() - 1;
+        let mut loop_start = self.current_chunk().code.len();
 
         // how do we know if we can exit the loop?
         // first we see if the next char is a semicolon
@@ -191,8 +429,8 @@ impl Compiler<'_> {
             self.consume(TokenType::Semicolon, "Expect ';' after loop condition");
             
 
-           exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse(0)));
-           self.emit_byte(OpCode::Pop); // pop condition
+           exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+           self.emit_op(OpCode::Pop); // pop condition
         }
         
     
@@ -201,45 +439,145 @@ impl Compiler<'_> {
             // jump over the incrementor the first time, execute the body
             // then jump BACK to the incrementor and reevaluate the condition
             // http://www.craftinginterpreters.com/image/jumping-back-and-forth/for.png
-            let body_jump = self.emit_jump(OpCode::Jump(0));
-            let increment_start = self.compiling_chunk.code.len() - 1;
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().code.len();
         
             self.expression();
-            self.emit_byte(OpCode::Pop);
+            self.emit_op(OpCode::Pop);
             self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
             self.emit_loop(loop_start);
             loop_start = increment_start;
 
             self.patch_jump(body_jump);
         }
+        // `continue` runs the incrementor next, when there is one
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
         
         // we jump to here in case we've finished the condition for looping
         if let Some(jump) = exit_jump {
             self.patch_jump(jump);
-            self.emit_byte(OpCode::Pop); // get the condition off stack
+            self.emit_op(OpCode::Pop); // get the condition off stack
         }
+        // breaks land past the condition pop, they leave the body with it already gone
+        self.end_loop();
         self.end_scope();
     }
 
     fn while_statement(&mut self) -> () {
         // this tells our loop where to run back to re-evaluate expression
-        let loop_start = self.compiling_chunk.code.len() - 1;
+        let loop_start = self.current_chunk().code.len();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
         // add a jump instruction to the end if while loop condition is false
-        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
         // if not false, clean up the jump
-        self.emit_byte(OpCode::Pop);
+        self.emit_op(OpCode::Pop);
+        self.begin_loop(loop_start);
         self.statement();
 
         // since we didn't exit we need to go back up and figure out how to re-run the statement
         self.emit_loop(loop_start);
         self.patch_jump(exit_jump);
-        self.emit_byte(OpCode::Pop);
+        self.emit_op(OpCode::Pop);
+        self.end_loop();
+    }
+
+    fn switch_statement(&mut self) -> () {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
+        self.begin_scope();
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after switch value.");
+
+        // the subject lives in a local without a name, so every case can compare
+        // against it without evaluating it again, and end_scope cleans it up
+        let mut subject = self.parser.previous.clone();
+        subject.lexeme = "".to_string();
+        let subject_slot = match self.add_local(subject) {
+            Some(slot) => {
+                self.mark_initialized();
+                slot
+            },
+            // out of slots, which is already reported and stops the script running,
+            // the cases only need something to compile against so they still parse cleanly
+            None => 0,
+        };
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch cases.");
+        let mut end_jumps = Vec::new();
+        let mut seen_default = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if self.match_token(TokenType::Case) {
+                if seen_default {
+                    self.error(ErrorCode::Syntax, "Cannot have a case after 'default'.");
+                }
+
+                // `case a, b:` tries each value in turn, any match runs the body
+                let mut body_jumps = Vec::new();
+                loop {
+                    self.emit_bytes(OpCode::GetLocal as u8, subject_slot);
+                    self.expression();
+                    self.emit_op(OpCode::Equal);
+                    let next_value = self.emit_jump(OpCode::JumpIfFalse);
+                    self.emit_op(OpCode::Pop);
+                    body_jumps.push(self.emit_jump(OpCode::Jump));
+                    self.patch_jump(next_value);
+                    self.emit_op(OpCode::Pop);
+
+                    if !self.match_token(TokenType::Comma) {
+                        break;
+                    }
+                }
+                self.consume(TokenType::Colon, "Expect ':' after case value.");
+
+                // nothing matched, go on to the next case
+                let next_case = self.emit_jump(OpCode::Jump);
+                for jump in body_jumps {
+                    self.patch_jump(jump);
+                }
+                self.case_body();
+                // no fallthrough, a finished case leaves the switch
+                end_jumps.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(next_case);
+            } else if self.match_token(TokenType::Default) {
+                if seen_default {
+                    self.error(ErrorCode::Syntax, "Cannot have more than one 'default' in a switch.");
+                }
+                seen_default = true;
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+                self.case_body();
+            } else {
+                self.error_at_current(ErrorCode::Syntax, "Expect 'case' or 'default' in switch.");
+                // skip ahead to something the switch understands instead of misreading the rest
+                while !self.check(TokenType::Case)
+                    && !self.check(TokenType::Default)
+                    && !self.check(TokenType::RightBrace)
+                    && !self.check(TokenType::EOF) {
+                    self.advance();
+                }
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after switch cases.");
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    // the statements up to the next case, each case gets a scope of its own
+    fn case_body(&mut self) -> () {
+        self.begin_scope();
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::EOF) {
+            self.declaration();
+        }
+        self.end_scope();
     }
 
     fn if_statement(&mut self) -> () {
@@ -247,14 +585,14 @@ impl Compiler<'_> {
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
-        let then_jump_patch_idx = self.emit_jump(OpCode::JumpIfFalse(0)); // emit placeholder
-        self.emit_byte(OpCode::Pop);
+        let then_jump_patch_idx = self.emit_jump(OpCode::JumpIfFalse); // emit placeholder
+        self.emit_op(OpCode::Pop);
         self.statement();
 
         // need the location of the end of the if consequence in order to else-patch
-        let else_jump_patch_idx = self.emit_jump(OpCode::Jump(0));
+        let else_jump_patch_idx = self.emit_jump(OpCode::Jump);
         self.patch_jump(then_jump_patch_idx);
-        self.emit_byte(OpCode::Pop);
+        self.emit_op(OpCode::Pop);
 
         if self.match_token(TokenType::Else) {
             self.statement();
@@ -262,26 +600,139 @@ impl Compiler<'_> {
         self.patch_jump(else_jump_patch_idx);
     }
 
+    // emits a jump with a placeholder distance and returns where the operand starts
     fn emit_jump(&mut self, jump_op: OpCode) -> usize {
-       self.emit_byte(jump_op);
-       self.compiling_chunk.code.len() - 1
+       self.emit_op(jump_op);
+       self.emit_bytes(0xff, 0xff);
+       self.current_chunk().code.len() - 2
     }
 
     fn emit_loop(&mut self, loop_start: usize) -> () {
-        let loop_offset = self.compiling_chunk.code.len() - loop_start;
-        
-        self.emit_byte(OpCode::Loop(loop_offset));
+        self.emit_op(OpCode::Loop);
+
+        // the VM has already read the two operand bytes by the time it jumps back
+        let loop_offset = self.current_chunk().code.len() - loop_start + 2;
+        if loop_offset > u16::MAX as usize {
+            self.error(ErrorCode::TooLarge, "Loop body too large.");
+        }
+
+        self.emit_bytes((loop_offset & 0xff) as u8, ((loop_offset >> 8) & 0xff) as u8);
     }
 
     fn patch_jump(&mut self, offset: usize) -> () {
-        let jump_length = self.compiling_chunk.code.len() - offset - 1;
-        let new_op = match self.compiling_chunk.code[offset].code {
-            OpCode::JumpIfFalse(0) => OpCode::JumpIfFalse(jump_length),
-            OpCode::Jump(0) => OpCode::Jump(jump_length),
-            _ => panic!("offset for patch_jump points to invalid instruction"),
+        // -2 to account for the operand bytes themselves
+        let jump_length = self.current_chunk().code.len() - offset - 2;
+        if jump_length > u16::MAX as usize {
+            self.error(ErrorCode::TooLarge, "Too much code to jump over.");
+        }
+
+        self.current_chunk().code[offset] = (jump_length & 0xff) as u8;
+        self.current_chunk().code[offset + 1] = ((jump_length >> 8) & 0xff) as u8;
+    }
+
+    fn class_declaration(&mut self) -> () {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::Class, name_constant);
+        self.define_variable(Some(name_constant));
+
+        self.classes.push(ClassCompiler { has_superclass: false });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name.lexeme == self.parser.previous.lexeme {
+                self.error(ErrorCode::InvalidInheritance, "A class cannot inherit from itself.");
+            }
+
+            // the superclass lives in a hidden local named `super` so methods can close over it
+            // the new scope keeps sibling classes from clobbering each other's `super`
+            self.begin_scope();
+            self.add_local(self.synthetic_token("super"));
+            self.define_variable(None);
+
+            self.named_variable(class_name.clone(), false);
+            self.emit_op(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // load the class back onto the stack so Method instructions can find it
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_op(OpCode::Pop);
+
+        if self.classes.last().unwrap().has_superclass {
+            self.end_scope();
+        }
+
+        self.classes.pop();
+    }
+
+    fn method(&mut self) -> () {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.identifier_constant(&self.parser.previous.clone());
+
+        let function_type = if self.parser.previous.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
         };
+        self.function(function_type);
+        self.emit_indexed(OpCode::Method, name);
+    }
+
+    fn fun_declaration(&mut self) -> () {
+        let global = self.parse_variable("Expect function name.");
+        // a function can refer to itself in its body, so it's usable before the body compiles
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) -> () {
+        let name = self.heap.intern(&self.parser.previous.lexeme);
+        self.functions.push(FunctionCompiler::new(function_type, Some(name), self.file));
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.current_mut().function.arity += 1;
+                if self.current().function.arity > 255 {
+                    self.error_at_current(ErrorCode::TooLarge, "Cannot have more than 255 parameters.");
+                }
+
+                let param = self.parse_variable("Expect parameter name.");
+                self.define_variable(param);
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
 
-        self.compiling_chunk.code[offset].code = new_op;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // no end_scope here, the whole frame gets thrown away when the function returns
+        let (function, upvalues) = self.end_compiler();
+        let function = self.heap.alloc(Obj::Function(function));
+        let constant = self.make_constant(Value::function_val(function));
+        self.emit_indexed(OpCode::Closure, constant);
+
+        // the VM reads one pair per upvalue, the function knows how many to expect
+        for upvalue in upvalues {
+            self.emit_bytes(if upvalue.is_local { 1 } else { 0 }, upvalue.index as u8);
+        }
     }
 
     fn var_declaration(&mut self) -> () {
@@ -290,7 +741,7 @@ impl Compiler<'_> {
         if self.match_token(TokenType::Equal) {
             self.expression()
         } else {
-            self.emit_byte(OpCode::Nil);
+            self.emit_op(OpCode::Nil);
         }
 
         self.consume(TokenType::Semicolon, "Expect ';' after variable declaration");
@@ -301,7 +752,7 @@ impl Compiler<'_> {
     fn expression_statement(&mut self) -> () {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_byte(OpCode::Pop);
+        self.emit_op(OpCode::Pop);
     }
 
     fn reset_error_state(&mut self) -> () {
@@ -309,64 +760,95 @@ impl Compiler<'_> {
         self.parser.had_error = false;
     }
 
-    fn parse_variable(&mut self, msg: &str) -> String {
+    // returns the constant index of the global's name, or None when the variable is a local
+    fn parse_variable(&mut self, msg: &str) -> Option<usize> {
         self.consume(TokenType::Identifier, msg);
 
         self.declare_variable();
-        if self.scope_depth > 0 {
-            // todo - stop using this dummy value
-            "".to_string()
+        if self.current().scope_depth > 0 {
+            None
         } else {
-            self.identifier_constant(&self.parser.previous)
+            Some(self.identifier_constant(&self.parser.previous.clone()))
         }
     }
 
-    fn define_variable(&mut self, global: String) -> () {
-        if self.scope_depth > 0 {
+    fn define_variable(&mut self, global: Option<usize>) -> () {
+        if self.current().scope_depth > 0 {
             self.mark_initialized();
             return ();
         }
-        self.emit_byte(OpCode::DefineGlobal(global));
+
+        if let Some(global) = global {
+            self.emit_indexed(OpCode::DefineGlobal, global);
+        }
     }
 
     fn mark_initialized(&mut self) -> () {
-        let len = self.locals.len();
-        self.locals[len - 1].initialized = true;
+        // globals are late bound, there's no local slot to mark
+        if self.current().scope_depth == 0 {
+            return;
+        }
+        let len = self.current().locals.len();
+        self.current_mut().locals[len - 1].initialized = true;
     }
 
     fn declare_variable(&mut self) -> () {
-        if self.scope_depth == 0 {
+        if self.current().scope_depth == 0 {
             return;
         }
 
         let token = self.parser.previous.clone();
-        let locals = self.locals.clone();
+        let locals = self.current().locals.clone();
+        let scope_depth = self.current().scope_depth;
 
         for local in locals.iter().rev() {
-            if local.initialized && local.depth < self.scope_depth {
+            if local.initialized && local.depth < scope_depth {
                 break;
             } 
 
             if local.name == token.lexeme {
-                self.error("Variable with this name already declared in this scope.");
+                self.error(ErrorCode::DuplicateVariable, "Variable with this name already declared in this scope.");
             }
         }
 
         self.add_local(token);
     }
 
-    fn add_local(&mut self, token: Token) -> () {
+    // the slot the new local sits in, None if the function is out of slots
+    fn add_local(&mut self, token: Token) -> Option<u8> {
+        // local slots are addressed by a single byte operand
+        let slot = self.current().locals.len();
+        if slot == 256 {
+            self.error(ErrorCode::TooLarge, "Too many local variables in function.");
+            return None;
+        }
+
         let local = Local {
             name: token.lexeme.clone(),
-            depth: self.scope_depth,
+            depth: self.current().scope_depth,
             initialized: false,
+            is_captured: false,
         };
 
-        self.locals.push(local);
+        self.current_mut().locals.push(local);
+        Some(slot as u8)
+    }
+
+    // a token that never appeared in the source, for variables the compiler declares itself
+    fn synthetic_token(&self, text: &str) -> Token {
+        Token {
+            token_type: TokenType::Identifier,
+            line: self.parser.previous.line,
+            column: self.parser.previous.column,
+            lexeme: text.to_string(),
+            start: self.parser.previous.start,
+            length: 0,
+        }
     }
 
-    fn identifier_constant(&self, token: &Token) -> String {
-        token.lexeme.clone()
+    fn identifier_constant(&mut self, token: &Token) -> usize {
+        let name = self.heap.intern(&token.lexeme);
+        self.make_constant(Value::string_val(name))
     }
 
     fn advance(&mut self) -> () {
@@ -377,7 +859,7 @@ impl Compiler<'_> {
             if self.parser.current.token_type != TokenType::Error {
                 break ()
             }
-            self.error_at_current(&self.parser.current.lexeme.clone());
+            self.error_at_current(ErrorCode::Lexical, &self.parser.current.lexeme.clone());
         }
     }
 
@@ -397,7 +879,12 @@ impl Compiler<'_> {
                     | TokenType::If
                     | TokenType::While
                     | TokenType::Print
-                    | TokenType::Return => return (),
+                    | TokenType::Return
+                    | TokenType::Break
+                    | TokenType::Continue
+                    | TokenType::Switch
+                    | TokenType::Case
+                    | TokenType::Default => return (),
                 _ => (),
             }
 
@@ -409,7 +896,7 @@ impl Compiler<'_> {
         if self.parser.current.token_type == token_type {
             self.advance();
         } else {
-            self.error_at_current(&msg);
+            self.error_at_current(ErrorCode::Syntax, msg);
         }
     }
 
@@ -426,43 +913,94 @@ impl Compiler<'_> {
         self.parser.current.token_type == token_type
     }
 
-    fn error_at_current(&mut self, msg: &str) -> () {
-        self.error_at(self.parser.current.clone(), &msg)
+    fn error_at_current(&mut self, code: ErrorCode, msg: &str) -> () {
+        self.error_at(self.parser.current.clone(), code, msg)
     }
 
-    fn error(&mut self, msg: &str) -> () {
-        self.error_at(self.parser.previous.clone(), &msg)
+    fn error(&mut self, code: ErrorCode, msg: &str) -> () {
+        self.error_at(self.parser.previous.clone(), code, msg)
     }
 
-    fn error_at(&mut self, token: Token, msg: &str) -> () {
+    fn error_at(&mut self, token: Token, code: ErrorCode, msg: &str) -> () {
         if self.parser.panic_mode { return }; 
         self.parser.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
-        match token.token_type {
-            TokenType::EOF => eprint!(" at end"),
-            _ => (),
 
+        let diagnostic = Diagnostic::error(code, msg)
+            .at_line(token.line)
+            .at_column(token.column)
+            .with_span(token.span());
+        self.diagnostics.push(diagnostic);
+        self.parser.had_error = true;
+    }
+
+    // instructions are tagged with the token they came from
+    // usually that's the one just consumed, the _at variants name a different one
+    fn location_of(token: &Token) -> Location {
+        Location {
+            line: token.line,
+            column: token.column,
+            span: token.span(),
         }
+    }
 
-        eprintln!(": {}", msg);
-        self.parser.had_error = true;
+    fn emit_byte(&mut self, byte: u8) -> () {
+        let location = Compiler::location_of(&self.parser.previous);
+        self.emit_byte_at(byte, location);
+    }
+
+    fn emit_byte_at(&mut self, byte: u8, location: Location) -> () {
+        self.current_chunk().write(byte, location);
     }
 
-    fn emit_byte(&mut self, code: OpCode) -> () {
-        self.compiling_chunk.write(code, self.parser.previous.line);
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) -> () {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
     }
 
-    fn emit_bytes(&mut self, code1: OpCode, code2: OpCode) -> () {
-        self.emit_byte(code1);
-        self.emit_byte(code2);
+    fn emit_op(&mut self, op: OpCode) -> () {
+        self.emit_byte(op as u8);
     }
 
-    //fn current_chunk(&self) -> Chunk {
-      //  self.compiling_chunk
-    //}
+    fn emit_op_at(&mut self, op: OpCode, location: Location) -> () {
+        self.emit_byte_at(op as u8, location);
+    }
+
+    fn emit_ops(&mut self, op1: OpCode, op2: OpCode) -> () {
+        self.emit_op(op1);
+        self.emit_op(op2);
+    }
 
-    fn end_compiler(&mut self) -> () {
+    // emits an instruction that takes a constant index, switching to the
+    // three byte long form once the index no longer fits in one byte
+    fn emit_indexed(&mut self, op: OpCode, index: usize) -> () {
+        let location = Compiler::location_of(&self.parser.previous);
+        self.emit_indexed_at(op, index, location);
+    }
+
+    fn emit_indexed_at(&mut self, op: OpCode, index: usize, location: Location) -> () {
+        if index <= u8::MAX as usize {
+            self.emit_op_at(op, location);
+            self.emit_byte_at(index as u8, location);
+            return;
+        }
+
+        match op.long_form() {
+            Some(long) => {
+                self.emit_op_at(long, location);
+                self.emit_byte_at((index & 0xff) as u8, location);
+                self.emit_byte_at(((index >> 8) & 0xff) as u8, location);
+                self.emit_byte_at(((index >> 16) & 0xff) as u8, location);
+            },
+            None => panic!("emit_indexed called with {:?}, which has no long form", op),
+        }
+    }
+
+    fn end_compiler(&mut self) -> (Function, Vec<UpvalueCapture>) {
         self.emit_return();
+        let compiler = self.functions.pop().unwrap();
+        let mut function = compiler.function;
+        function.upvalue_count = compiler.upvalues.len();
+        (function, compiler.upvalues)
     }
     
     fn get_rule(&self, op_type: TokenType) -> ParseRule {
@@ -471,77 +1009,471 @@ impl Compiler<'_> {
 
     fn binary(&mut self, _can_assign: bool) -> () {
         let op_type : TokenType = self.parser.previous.token_type;
+        // runtime type errors should point at the operator, not the right operand
+        let operator = Compiler::location_of(&self.parser.previous);
         let rule : ParseRule = self.get_rule(op_type);
-        self.parse_precedence(Precedence::next_rule(rule));
+        if op_type == TokenType::StarStar {
+            // right associative, and the exponent may have a sign: 2 ** -1
+            self.parse_precedence(Precedence::Unary);
+        } else {
+            self.parse_precedence(Precedence::next_rule(rule));
+        }
         match op_type {
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
-            TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater, OpCode::Not),
+            TokenType::Plus => self.emit_op_at(OpCode::Add, operator),
+            TokenType::Minus => self.emit_op_at(OpCode::Subtract, operator),
+            TokenType::Star => self.emit_op_at(OpCode::Multiply, operator),
+            TokenType::Slash => self.emit_op_at(OpCode::Divide, operator),
+            TokenType::Percent => self.emit_op_at(OpCode::Modulo, operator),
+            TokenType::StarStar => self.emit_op_at(OpCode::Power, operator),
+            TokenType::Ampersand => self.emit_op_at(OpCode::BitAnd, operator),
+            TokenType::Pipe => self.emit_op_at(OpCode::BitOr, operator),
+            TokenType::Caret => self.emit_op_at(OpCode::BitXor, operator),
+            TokenType::LessLess => self.emit_op_at(OpCode::ShiftLeft, operator),
+            TokenType::GreaterGreater => self.emit_op_at(OpCode::ShiftRight, operator),
+            TokenType::BangEqual => {
+                self.emit_op_at(OpCode::Equal, operator);
+                self.emit_op_at(OpCode::Not, operator);
+            },
+            TokenType::EqualEqual => self.emit_op_at(OpCode::Equal, operator),
+            TokenType::Greater => self.emit_op_at(OpCode::Greater, operator),
+            TokenType::GreaterEqual => {
+                self.emit_op_at(OpCode::Less, operator);
+                self.emit_op_at(OpCode::Not, operator);
+            },
+            TokenType::Less => self.emit_op_at(OpCode::Less, operator),
+            TokenType::LessEqual => {
+                self.emit_op_at(OpCode::Greater, operator);
+                self.emit_op_at(OpCode::Not, operator);
+            },
             _ => (),
         }
     }
 
+    fn call(&mut self, _can_assign: bool) -> () {
+        // arity errors and friends point at the opening paren of the call
+        let paren = Compiler::location_of(&self.parser.previous);
+        let arg_count = self.argument_list();
+        self.emit_op_at(OpCode::Call, paren);
+        self.emit_byte_at(arg_count as u8, paren);
+    }
+
+    fn argument_list(&mut self) -> usize {
+        let mut arg_count = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error(ErrorCode::TooLarge, "Cannot have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count
+    }
+
+    // `[a, b, c]`, a trailing comma is allowed
+    fn list(&mut self, _can_assign: bool) -> () {
+        let bracket = Compiler::location_of(&self.parser.previous);
+        let mut count = 0;
+        while !self.check(TokenType::RightBracket) && !self.check(TokenType::EOF) {
+            self.expression();
+            if count == 255 {
+                self.error(ErrorCode::TooLarge, "Cannot have more than 255 items in a list literal.");
+            }
+            count += 1;
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit_op_at(OpCode::BuildList, bracket);
+        self.emit_byte_at(count as u8, bracket);
+    }
+
+    // `{key: value, ...}`, a trailing comma is allowed
+    fn map(&mut self, _can_assign: bool) -> () {
+        let brace = Compiler::location_of(&self.parser.previous);
+        let mut count = 0;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.expression();
+            self.consume(TokenType::Colon, "Expect ':' after map key.");
+            self.expression();
+            if count == 255 {
+                self.error(ErrorCode::TooLarge, "Cannot have more than 255 entries in a map literal.");
+            }
+            count += 1;
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit_op_at(OpCode::BuildMap, brace);
+        self.emit_byte_at(count as u8, brace);
+    }
+
+    // `xs[i]` and `xs[i] = value`, for lists and maps
+    fn subscript(&mut self, can_assign: bool) -> () {
+        // index errors point at the opening bracket
+        let bracket = Compiler::location_of(&self.parser.previous);
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_op_at(OpCode::SetIndex, bracket);
+        } else if let Some(op) = self.compound_operator(can_assign) {
+            let operator = Compiler::location_of(&self.parser.previous);
+            self.emit_update(Target::Index(bracket), Update::Compound(op), operator);
+        } else if self.match_token(TokenType::PlusPlus) || self.match_token(TokenType::MinusMinus) {
+            let operator = Compiler::location_of(&self.parser.previous);
+            let op = Compiler::step_operator(self.parser.previous.token_type);
+            self.emit_update(Target::Index(bracket), Update::Postfix(op), operator);
+        } else {
+            self.emit_op_at(OpCode::GetIndex, bracket);
+        }
+    }
+
+    fn dot(&mut self, can_assign: bool) -> () {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(&self.parser.previous.clone());
+        let property = Compiler::location_of(&self.parser.previous);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed_at(OpCode::SetProperty, name, property);
+        } else if let Some(op) = self.compound_operator(can_assign) {
+            let operator = Compiler::location_of(&self.parser.previous);
+            self.emit_update(Target::Field(name, property), Update::Compound(op), operator);
+        } else if self.match_token(TokenType::PlusPlus) || self.match_token(TokenType::MinusMinus) {
+            let operator = Compiler::location_of(&self.parser.previous);
+            let op = Compiler::step_operator(self.parser.previous.token_type);
+            self.emit_update(Target::Field(name, property), Update::Postfix(op), operator);
+        } else if self.match_token(TokenType::LeftParen) {
+            // calling a method straight off an instance skips creating a bound method
+            let arg_count = self.argument_list();
+            self.emit_indexed_at(OpCode::Invoke, name, property);
+            self.emit_byte_at(arg_count as u8, property);
+        } else {
+            self.emit_indexed(OpCode::GetProperty, name);
+        }
+    }
+
     fn literal(&mut self, _can_assign: bool) -> () {
         match self.parser.previous.token_type {
-            TokenType::False => self.emit_byte(OpCode::False),
-            TokenType::True => self.emit_byte(OpCode::True),
-            TokenType::Nil => self.emit_byte(OpCode::Nil),
+            TokenType::False => self.emit_op(OpCode::False),
+            TokenType::True => self.emit_op(OpCode::True),
+            TokenType::Nil => self.emit_op(OpCode::Nil),
             _ => (),
         }
     }
 
+    fn integer(&mut self, _can_assign: bool) -> () {
+        match scanner::parse_integer(&self.parser.previous.lexeme) {
+            Ok(value) => self.emit_constant(Value::int_val(value)),
+            Err(message) => self.error(ErrorCode::Lexical, &message),
+        }
+    }
+
     fn number(&mut self, _can_assign: bool) -> () {
-        let value = self.parser.previous.lexeme.parse::<f64>().unwrap();
-        let val = Value::number_val(value);
-        self.emit_constant(val);
+        match scanner::parse_number(&self.parser.previous.lexeme) {
+            Ok(value) => self.emit_constant(Value::number_val(value)),
+            Err(message) => self.error(ErrorCode::Lexical, &message),
+        }
     }
 
     fn string(&mut self, _can_assign: bool) {
-        let lexeme = self.parser.previous.lexeme.parse::<String>().unwrap();
-        let len = lexeme.len();
-        let value = &lexeme[1..len - 1];
-        let val = Value::string_val(value.to_string());
+        let value = self.string_text(&self.parser.previous.clone());
+        let val = Value::string_val(self.heap.intern(&value));
         self.emit_constant(val);
     }
 
+    // the characters a string or interpolation token stands for
+    // both start with `"` or the `}` closing an interpolation, and end with `"` or `${`
+    fn string_text(&self, token: &Token) -> String {
+        let lexeme = &token.lexeme;
+        let end = match token.token_type {
+            TokenType::Interpolation => lexeme.len() - 2,
+            _ => lexeme.len() - 1,
+        };
+        scanner::unescape(&lexeme[1..end])
+    }
+
+    // "a ${x} b" compiles as "a " + str(x) + " b", each piece arriving as its own token
+    fn interpolation(&mut self, _can_assign: bool) -> () {
+        self.string(false);
+        loop {
+            // `${}` ends the interpolation straight away, which would otherwise parse as a string
+            let current = &self.parser.current;
+            let closes = matches!(current.token_type, TokenType::TokenString | TokenType::Interpolation);
+            if closes && current.lexeme.starts_with('}') {
+                self.error_at_current(ErrorCode::Syntax, "Expect expression.");
+                return;
+            }
+
+            self.expression();
+            self.emit_op(OpCode::Stringify);
+            self.emit_op(OpCode::Add);
+
+            let more = self.match_token(TokenType::Interpolation);
+            if !more && !self.match_token(TokenType::TokenString) {
+                self.error_at_current(ErrorCode::Syntax, "Expect '}' after interpolated expression.");
+                return;
+            }
+            // the empty pieces between back to back interpolations are skipped
+            if !self.string_text(&self.parser.previous.clone()).is_empty() {
+                self.string(false);
+                self.emit_op(OpCode::Add);
+            }
+            if !more {
+                break;
+            }
+        }
+    }
+
     fn variable(&mut self, can_assign: bool) -> () {
         self.named_variable(self.parser.previous.clone(), can_assign);
     }
 
-    fn named_variable(&mut self, token: Token, can_assign: bool) -> () {
+    fn super_(&mut self, _can_assign: bool) -> () {
+        match self.classes.last() {
+            None => self.error(ErrorCode::InvalidSuper, "Cannot use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => self.error(ErrorCode::InvalidSuper, "Cannot use 'super' in a class with no superclass."),
+            _ => (),
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(&self.parser.previous.clone());
+
+        // the receiver goes on the stack first, then the superclass to look the method up in
+        self.named_variable(self.synthetic_token("this"), false);
+        if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(self.synthetic_token("super"), false);
+            self.emit_indexed(OpCode::SuperInvoke, name);
+            self.emit_byte(arg_count as u8);
+        } else {
+            self.named_variable(self.synthetic_token("super"), false);
+            self.emit_indexed(OpCode::GetSuper, name);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) -> () {
+        if self.classes.is_empty() {
+            self.error(ErrorCode::InvalidThis, "Cannot use 'this' outside of a class.");
+            return;
+        }
+
+        // `this` is just a read only local living in slot zero
+        self.variable(false);
+    }
+
+    // the instructions for reading and writing the variable `token` names, and their operand
+    fn resolve_variable(&mut self, token: &Token) -> (OpCode, OpCode, usize) {
         let get_op;
         let set_op;
+        let arg;
         
-        if let Some(arg) = self.resolve_local(&token) {
-            set_op = OpCode::SetLocal(arg);
-            get_op = OpCode::GetLocal(arg);
+        let current = self.functions.len() - 1;
+        if let Some(slot) = self.resolve_local(current, token) {
+            set_op = OpCode::SetLocal;
+            get_op = OpCode::GetLocal;
+            arg = slot;
+        } else if let Some(index) = self.resolve_upvalue(current, token) {
+            set_op = OpCode::SetUpvalue;
+            get_op = OpCode::GetUpvalue;
+            arg = index;
         } else {
-            let arg = self.identifier_constant(&token);
-            set_op = OpCode::SetGlobal(arg.clone());
-            get_op = OpCode::GetGlobal(arg.clone());
+            set_op = OpCode::SetGlobal;
+            get_op = OpCode::GetGlobal;
+            arg = self.identifier_constant(token);
         }
+        (get_op, set_op, arg)
+    }
+
+    fn named_variable(&mut self, token: Token, can_assign: bool) -> () {
+        let (get_op, set_op, arg) = self.resolve_variable(&token);
         // we'll check for setters vs getters
+        // locals and upvalues always fit in a byte, only globals can need the long form
+        let location = Compiler::location_of(&token);
         if can_assign && self.match_token(TokenType::Equal) {
             self.expression();
-            self.emit_byte(set_op);
+            self.emit_indexed_at(set_op, arg, location);
+        } else if let Some(op) = self.compound_operator(can_assign) {
+            // `x += y` reads x, combines it with y and writes the result back
+            let operator = Compiler::location_of(&self.parser.previous);
+            self.emit_indexed_at(get_op, arg, location);
+            self.expression();
+            self.emit_op_at(op, operator);
+            self.emit_indexed_at(set_op, arg, location);
+        } else if self.match_token(TokenType::PlusPlus) || self.match_token(TokenType::MinusMinus) {
+            // postfix: the old value is left behind as the result, under the update
+            let operator = Compiler::location_of(&self.parser.previous);
+            let op = Compiler::step_operator(self.parser.previous.token_type);
+            self.emit_indexed_at(get_op, arg, location);
+            self.emit_indexed_at(get_op, arg, location);
+            self.emit_constant(Value::int_val(1));
+            self.emit_op_at(op, operator);
+            self.emit_indexed_at(set_op, arg, location);
+            self.emit_op(OpCode::Pop);
         } else {
-            self.emit_byte(get_op);
+            self.emit_indexed_at(get_op, arg, location);
         }
     }
 
-    fn resolve_local(&mut self, token: &Token) -> Option<usize> {
-        let locals = self.locals.clone();
+    // consumes a `+=` style operator, returning the arithmetic it stands for
+    fn compound_operator(&mut self, can_assign: bool) -> Option<OpCode> {
+        if !can_assign {
+            return None;
+        }
+
+        let op = match self.parser.current.token_type {
+            TokenType::PlusEqual => OpCode::Add,
+            TokenType::MinusEqual => OpCode::Subtract,
+            TokenType::StarEqual => OpCode::Multiply,
+            TokenType::SlashEqual => OpCode::Divide,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    fn step_operator(token_type: TokenType) -> OpCode {
+        match token_type {
+            TokenType::PlusPlus => OpCode::Add,
+            _ => OpCode::Subtract,
+        }
+    }
+
+    // `++x` and `--x`, which evaluate to the updated value
+    // the target can also end in a field or an index, as in `++this.count` or `--xs[i]`,
+    // reached through any chain of calls, fields and indexes; only the last link is stepped
+    fn prefix_step(&mut self, _can_assign: bool) -> () {
+        let operator_type = self.parser.previous.token_type;
+        let operator = Compiler::location_of(&self.parser.previous);
+        let op = Compiler::step_operator(operator_type);
+
+        if self.match_token(TokenType::This) {
+            self.this(false);
+        } else {
+            self.consume(TokenType::Identifier, "Expect variable name after increment or decrement.");
+            let token = self.parser.previous.clone();
+            let (get_op, set_op, arg) = self.resolve_variable(&token);
+            let location = Compiler::location_of(&token);
+
+            self.emit_indexed_at(get_op, arg, location);
+            if !self.at_accessor() {
+                self.emit_constant(Value::int_val(1));
+                self.emit_op_at(op, operator);
+                self.emit_indexed_at(set_op, arg, location);
+                return;
+            }
+        }
+
+        loop {
+            if self.match_token(TokenType::LeftParen) {
+                self.call(false);
+            } else if self.match_token(TokenType::Dot) {
+                self.consume(TokenType::Identifier, "Expect property name after '.'.");
+                let name = self.identifier_constant(&self.parser.previous.clone());
+                let property = Compiler::location_of(&self.parser.previous);
+                if !self.at_accessor() {
+                    self.emit_update(Target::Field(name, property), Update::Prefix(op), operator);
+                    return;
+                }
+                self.emit_indexed_at(OpCode::GetProperty, name, property);
+            } else if self.match_token(TokenType::LeftBracket) {
+                let bracket = Compiler::location_of(&self.parser.previous);
+                self.expression();
+                self.consume(TokenType::RightBracket, "Expect ']' after index.");
+                if !self.at_accessor() {
+                    self.emit_update(Target::Index(bracket), Update::Prefix(op), operator);
+                    return;
+                }
+                self.emit_op_at(OpCode::GetIndex, bracket);
+            } else {
+                // a call result, or `this` on its own
+                self.error(ErrorCode::InvalidAssignment, "Invalid increment or decrement target.");
+                return;
+            }
+        }
+    }
+
+    // whether the expression so far carries on with a call, a field or an index
+    fn at_accessor(&self) -> bool {
+        self.check(TokenType::LeftParen) || self.check(TokenType::Dot) || self.check(TokenType::LeftBracket)
+    }
+
+    // reads the target, combines it with the update's operand and writes it back
+    // the instance, or the collection and index, are copied first, the write consumes the copies
+    //
+    //   postfix on a field:  [obj] dup 0, get, bury 1 -> [old, obj] dup 1, 1, add, set, pop -> [old]
+    // arithmetic errors point at `operator`
+    fn emit_update(&mut self, target: Target, update: Update, operator: Location) -> () {
+        let (depth, location) = match target {
+            Target::Field(_, location) => (1, location),
+            Target::Index(location) => (2, location),
+        };
+
+        for _ in 0..depth {
+            self.emit_op_at(OpCode::Dup, location);
+            self.emit_byte_at(depth - 1, location);
+        }
+        self.emit_target(target, OpCode::GetProperty, OpCode::GetIndex);
+
+        let op = match update {
+            Update::Compound(op) => {
+                self.expression();
+                op
+            },
+            Update::Prefix(op) => {
+                self.emit_constant(Value::int_val(1));
+                op
+            },
+            Update::Postfix(op) => {
+                // the old value goes under the target, to be left behind as the result
+                self.emit_op_at(OpCode::Bury, location);
+                self.emit_byte_at(depth, location);
+                self.emit_op_at(OpCode::Dup, location);
+                self.emit_byte_at(depth, location);
+                self.emit_constant(Value::int_val(1));
+                op
+            },
+        };
+        self.emit_op_at(op, operator);
+        self.emit_target(target, OpCode::SetProperty, OpCode::SetIndex);
+
+        if let Update::Postfix(_) = update {
+            self.emit_op(OpCode::Pop);
+        }
+    }
+
+    fn emit_target(&mut self, target: Target, field_op: OpCode, index_op: OpCode) -> () {
+        match target {
+            Target::Field(name, location) => self.emit_indexed_at(field_op, name, location),
+            Target::Index(location) => self.emit_op_at(index_op, location),
+        }
+    }
+
+    // postfix steps on variables, fields and indexes are handled where those are parsed,
+    // anything reaching here isn't one
+    fn postfix_step(&mut self, _can_assign: bool) -> () {
+        self.error(ErrorCode::InvalidAssignment, "Invalid increment or decrement target.");
+    }
+
+    fn resolve_local(&mut self, compiler: usize, token: &Token) -> Option<usize> {
+        let locals = self.functions[compiler].locals.clone();
         for (idx, local) in locals.iter().enumerate().rev() {
             if local.name == token.lexeme {
                 if !local.initialized {
-                    self.error("Cannot read local variable in its own initializer.");
+                    self.error(ErrorCode::UninitializedVariable, "Cannot read local variable in its own initializer.");
                 }
                 return Some(idx);
             } 
@@ -550,6 +1482,45 @@ impl Compiler<'_> {
         None
     }
 
+    // walks outward through the enclosing functions looking for a local to close over
+    // every function in between gets its own upvalue so the value can be threaded down
+    fn resolve_upvalue(&mut self, compiler: usize, token: &Token) -> Option<usize> {
+        if compiler == 0 {
+            // we've hit the top level script, anything out here is a global
+            return None;
+        }
+
+        let enclosing = compiler - 1;
+        if let Some(local) = self.resolve_local(enclosing, token) {
+            self.functions[enclosing].locals[local].is_captured = true;
+            return Some(self.add_upvalue(compiler, local, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, token) {
+            return Some(self.add_upvalue(compiler, upvalue, false));
+        }
+
+        None
+    }
+
+    fn add_upvalue(&mut self, compiler: usize, index: usize, is_local: bool) -> usize {
+        let upvalues = &self.functions[compiler].upvalues;
+        // a function that references the same variable twice only captures it once
+        for (idx, upvalue) in upvalues.iter().enumerate() {
+            if upvalue.index == index && upvalue.is_local == is_local {
+                return idx;
+            }
+        }
+
+        if upvalues.len() == 256 {
+            self.error(ErrorCode::TooLarge, "Too many closure variables in function.");
+            return 0;
+        }
+
+        self.functions[compiler].upvalues.push(UpvalueCapture { is_local, index });
+        self.functions[compiler].upvalues.len() - 1
+    }
+
     fn grouping(&mut self, _can_assign: bool) -> () {
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
@@ -557,10 +1528,12 @@ impl Compiler<'_> {
 
     fn unary(&mut self, _can_assign: bool) -> () {
         let last_seen_type = self.parser.previous.token_type;
+        let operator = Compiler::location_of(&self.parser.previous);
         self.parse_precedence(Precedence::Unary);
         match last_seen_type {
-            TokenType::Bang => self.emit_byte(OpCode::Not),
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
+            TokenType::Bang => self.emit_op_at(OpCode::Not, operator),
+            TokenType::Minus => self.emit_op_at(OpCode::Negate, operator),
+            TokenType::Tilde => self.emit_op_at(OpCode::BitNot, operator),
             _ => (),
         }
     }
@@ -571,7 +1544,7 @@ impl Compiler<'_> {
         let can_assign = precedence <= Precedence::Assignment;
 
         match prefix_rule {
-            None => self.error("Expect expression."),
+            None => self.error(ErrorCode::Syntax, "Expect expression."),
             Some(parse_fn) => parse_fn(self, can_assign),
         }
         
@@ -579,19 +1552,19 @@ impl Compiler<'_> {
             self.advance();
             let infix_rule : Option<ParserFunction> = self.get_rule(self.parser.previous.token_type).infix;
             match infix_rule {
-                None => self.error("Unexpected call to infix rule"),
+                None => self.error(ErrorCode::Syntax, "Unexpected call to infix rule"),
                 Some(infix_fn) => infix_fn(self, can_assign),
             }
         }
 
-        if can_assign && self.match_token(TokenType::Equal) {
-            self.error("Invalid assignment target.");
+        if can_assign && (self.match_token(TokenType::Equal) || self.compound_operator(true).is_some()) {
+            self.error(ErrorCode::InvalidAssignment, "Invalid assignment target.");
         }
     }
 
     fn and(&mut self, _can_assign: bool) -> () {
-        let end_jump = self.emit_jump(OpCode::JumpIfFalse(0));
-        self.emit_byte(OpCode::Pop);
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
         self.parse_precedence(Precedence::And);
 
         self.patch_jump(end_jump);
@@ -602,11 +1575,11 @@ impl Compiler<'_> {
         // so else jump says "hey, if the top of the stack is false basically just keep going"
         // if the lefthand side is truthy, tthen it'll run into the end_jump jump which skips parsing the righthand side
 
-        let else_jump = self.emit_jump(OpCode::JumpIfFalse(0));
-        let end_jump = self.emit_jump(OpCode::Jump(0));
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
 
         self.patch_jump(else_jump);
-        self.emit_byte(OpCode::Pop);
+        self.emit_op(OpCode::Pop);
 
         self.parse_precedence(Precedence::Or);
 
@@ -618,11 +1591,28 @@ impl Compiler<'_> {
     }
 
     fn emit_return(&mut self) -> () {
-        self.emit_byte(OpCode::Return);
+        if self.current().function_type == FunctionType::Initializer {
+            // initializers always hand back the instance sitting in slot zero
+            self.emit_bytes(OpCode::GetLocal as u8, 0);
+            self.emit_op(OpCode::Return);
+        } else {
+            // falling off the end of a function implicitly returns nil
+            self.emit_ops(OpCode::Nil, OpCode::Return);
+        }
     }
 
     fn emit_constant(&mut self, value: Value) -> () {
-        self.emit_byte(OpCode::Constant(value));
+        let constant = self.make_constant(value);
+        self.emit_indexed(OpCode::Constant, constant);
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.current_chunk().add_constant(value);
+        if constant >= MAX_CONSTANTS {
+            self.error(ErrorCode::TooLarge, "Too many constants in one chunk.");
+            return 0;
+        }
+        constant
     }
 }
 
@@ -674,31 +1664,54 @@ impl ParseRule {
 }
 
 // maps are heap allocated so this is faster
-const RULES : [ParseRule; 40] = [
-    ParseRule::prefix(|compiler, can_assign| compiler.grouping(can_assign), Precedence::None), //left paren
+const RULES : [ParseRule; 64] = [
+    ParseRule::both(|compiler, can_assign| compiler.grouping(can_assign), |compiler, can_assign| compiler.call(can_assign), Precedence::Call), //left paren
     ParseRule::neither(), //right paren
-    ParseRule::neither(), //left brace
+    ParseRule::prefix(|compiler, can_assign| compiler.map(can_assign), Precedence::None), //left brace
     ParseRule::neither(), //right brace
+    ParseRule::both(|compiler, can_assign| compiler.list(can_assign), |compiler, can_assign| compiler.subscript(can_assign), Precedence::Call), // left bracket
+    ParseRule::neither(), // right bracket
     ParseRule::neither(), // comma
-    ParseRule::neither(), // dot
+    ParseRule::infix(|compiler, can_assign| compiler.dot(can_assign), Precedence::Call), // dot
     ParseRule::both(|compiler, can_assign| compiler.unary(can_assign), |compiler, can_assign| compiler.binary(can_assign), Precedence::Term), // minus
     ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Term), //plus
     ParseRule::neither(), //semicolon
     ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Factor), //slash
     ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Factor), //star
+    ParseRule::neither(), // colon
+    ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Factor), // percent
+    ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::BitAnd), // ampersand
+    ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::BitOr), // pipe
+    ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::BitXor), // caret
+    ParseRule::prefix(|compiler, can_assign| compiler.unary(can_assign), Precedence::None), // tilde
     ParseRule::prefix(|compiler, can_assign| compiler.unary(can_assign), Precedence::None), // bang
     ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Equality), //bang equal
     ParseRule::neither(), //equal
     ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Equality), //equal equal
     ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Comparison), // greater
     ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Comparison), //greater equal
+    ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Shift), // greater greater
     ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Comparison), //less
     ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Comparison), //less equal
+    ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Shift), // less less
+    ParseRule::neither(), // plus equal
+    ParseRule::both(|compiler, can_assign| compiler.prefix_step(can_assign), |compiler, can_assign| compiler.postfix_step(can_assign), Precedence::Call), // plus plus
+    ParseRule::neither(), // minus equal
+    ParseRule::both(|compiler, can_assign| compiler.prefix_step(can_assign), |compiler, can_assign| compiler.postfix_step(can_assign), Precedence::Call), // minus minus
+    ParseRule::neither(), // star equal
+    ParseRule::infix(|compiler, can_assign| compiler.binary(can_assign), Precedence::Exponent), // star star
+    ParseRule::neither(), // slash equal
     ParseRule::prefix(|compiler, can_assign| compiler.variable(can_assign), Precedence::None), //identifier
     ParseRule::prefix(|compiler, can_assign| compiler.string(can_assign), Precedence::None), //string
+    ParseRule::prefix(|compiler, can_assign| compiler.integer(can_assign), Precedence::None), // integer
     ParseRule::prefix(|compiler, can_assign| compiler.number(can_assign), Precedence::None), // number
+    ParseRule::prefix(|compiler, can_assign| compiler.interpolation(can_assign), Precedence::None), // interpolation
     ParseRule::infix(|compiler, _can_assign| compiler.and(_can_assign), Precedence::And), // and
+    ParseRule::neither(), // break
+    ParseRule::neither(), // case
     ParseRule::neither(), // class
+    ParseRule::neither(), // continue
+    ParseRule::neither(), // default
     ParseRule::neither(), // else
     ParseRule::prefix(|compiler, can_assign| compiler.literal(can_assign), Precedence::None), // false
     ParseRule::neither(), // for
@@ -708,8 +1721,9 @@ const RULES : [ParseRule; 40] = [
     ParseRule::infix(|compiler, _can_assign| compiler.or(_can_assign), Precedence::Or), // or
     ParseRule::neither(), // print
     ParseRule::neither(), // return
-    ParseRule::neither(), // super
-    ParseRule::neither(), // this 
+    ParseRule::prefix(|compiler, can_assign| compiler.super_(can_assign), Precedence::None), // super
+    ParseRule::neither(), // switch
+    ParseRule::prefix(|compiler, can_assign| compiler.this(can_assign), Precedence::None), // this
     ParseRule::prefix(|compiler, can_assign| compiler.literal(can_assign), Precedence::None), // true
     ParseRule::neither(), // var
     ParseRule::neither(), // while
@@ -0,0 +1,238 @@
+use std::fmt;
+
+use crate::unicode;
+
+// nothing raises warnings yet, but hosts already match on severity
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+// what kind of problem a diagnostic describes
+// the codes are stable, hosts can match on them instead of on message text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    // compile time
+    Lexical,
+    Syntax,
+    InvalidAssignment,
+    DuplicateVariable,
+    UninitializedVariable,
+    InvalidReturn,
+    InvalidThis,
+    InvalidSuper,
+    InvalidInheritance,
+    TooLarge,
+    InvalidLoopControl,
+
+    // run time
+    Type,
+    UndefinedVariable,
+    UndefinedProperty,
+    Arity,
+    StackOverflow,
+    Native,
+    InvalidBytecode,
+    Io,
+    Index,
+    Arithmetic,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Lexical => "E0001",
+            ErrorCode::Syntax => "E0002",
+            ErrorCode::InvalidAssignment => "E0003",
+            ErrorCode::DuplicateVariable => "E0004",
+            ErrorCode::UninitializedVariable => "E0005",
+            ErrorCode::InvalidReturn => "E0006",
+            ErrorCode::InvalidThis => "E0007",
+            ErrorCode::InvalidSuper => "E0008",
+            ErrorCode::InvalidInheritance => "E0009",
+            ErrorCode::TooLarge => "E0010",
+            ErrorCode::InvalidLoopControl => "E0011",
+            ErrorCode::Type => "E0100",
+            ErrorCode::UndefinedVariable => "E0101",
+            ErrorCode::UndefinedProperty => "E0102",
+            ErrorCode::Arity => "E0103",
+            ErrorCode::StackOverflow => "E0104",
+            ErrorCode::Native => "E0105",
+            ErrorCode::InvalidBytecode => "E0106",
+            ErrorCode::Io => "E0107",
+            ErrorCode::Index => "E0108",
+            ErrorCode::Arithmetic => "E0109",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// a range of characters in the source, end exclusive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+// one active call at the point a runtime error was raised
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFrame {
+    // None for top level script code
+    pub function: Option<String>,
+    pub file: String,
+    pub line: i32,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "at {}() ({}:{})", name, self.file, self.line),
+            None => write!(f, "at script ({}:{})", self.file, self.line),
+        }
+    }
+}
+
+// a problem found while compiling or running a script
+// nothing gets printed when one is raised, it's up to the host to report it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    // both 1 based, and missing when the problem isn't tied to a place in the source
+    pub line: Option<i32>,
+    pub column: Option<usize>,
+    pub span: Option<Span>,
+    // the call stack for runtime errors, innermost call first; empty for compile errors
+    pub trace: Vec<TraceFrame>,
+}
+
+impl Diagnostic {
+    pub fn error(code: ErrorCode, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message: message.to_string(),
+            line: None,
+            column: None,
+            span: None,
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn at_line(mut self, line: i32) -> Diagnostic {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn at_column(mut self, column: usize) -> Diagnostic {
+        self.column = Some(column);
+        self
+    }
+
+    pub fn with_span(mut self, span: Span) -> Diagnostic {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_trace(mut self, trace: Vec<TraceFrame>) -> Diagnostic {
+        self.trace = trace;
+        self
+    }
+
+    fn write_trace(&self, out: &mut String) -> () {
+        for frame in self.trace.iter() {
+            out.push_str(&format!("\n    {}", frame));
+        }
+    }
+}
+
+impl Diagnostic {
+    // where in `source` the problem is, as " at 'lexeme'" or " at end"
+    // empty when there's no span, and for lexical errors whose message already says what was wrong
+    pub fn location_in(&self, source: &str) -> String {
+        let span = match self.span {
+            Some(span) => span,
+            None => return "".to_string(),
+        };
+
+        if span.start >= span.end {
+            " at end".to_string()
+        } else if self.code == ErrorCode::Lexical {
+            "".to_string()
+        } else {
+            let lexeme: String = source.chars().skip(span.start).take(span.end - span.start).collect();
+            format!(" at '{}'", lexeme)
+        }
+    }
+
+    // the diagnostic followed by the offending source line, with the span underlined
+    //
+    //   [line 2:9] Error[E0002] at ';': Expect expression.
+    //     |
+    //   2 | var x = ;
+    //     |         ^
+    //
+    // `source` has to be the text the diagnostic was raised against
+    pub fn render(&self, source: &str) -> String {
+        let (line, column, span) = match (self.line, self.column, self.span) {
+            (Some(line), Some(column), Some(span)) => (line, column, span),
+            _ => return format!("{}", self),
+        };
+        // errors at the end of a file ending in a newline sit on the empty line after it
+        let text = match source.lines().nth((line - 1) as usize) {
+            Some(text) => text,
+            None if line as usize == source.lines().count() + 1 => "",
+            None => return format!("{}", self),
+        };
+
+        let location = self.location_in(source);
+        let mut out = format!("[line {}:{}] {}[{}]{}: {}\n", line, column, self.severity, self.code, location, self.message);
+
+        let gutter = " ".repeat(line.to_string().len());
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line, text));
+
+        // keep tabs and count wide characters twice so the carets line up with what the terminal shows
+        let text: Vec<char> = text.chars().collect();
+        let before = std::cmp::min(column.saturating_sub(1), text.len());
+        let padding: String = text[..before].iter()
+            .map(|ch| if *ch == '\t' { "\t".to_string() } else { " ".repeat(unicode::display_width(*ch)) })
+            .collect();
+        // spans running past the end of the line only get underlined up to it
+        let end = std::cmp::min(before + span.end - span.start, text.len());
+        let width: usize = text[before..end].iter().map(|ch| unicode::display_width(*ch)).sum();
+        out.push_str(&format!("{} | {}{}", gutter, padding, "^".repeat(std::cmp::max(1, width))));
+        self.write_trace(&mut out);
+        out
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "[line {}:{}] ", line, column)?,
+            (Some(line), None) => write!(f, "[line {}] ", line)?,
+            _ => (),
+        }
+        let mut trace = String::new();
+        self.write_trace(&mut trace);
+        write!(f, "{}[{}]: {}{}", self.severity, self.code, self.message, trace)
+    }
+}
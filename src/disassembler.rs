@@ -1,67 +1,156 @@
 use crate::chunk::{Chunk, OpCode};
-use crate::value::{Value};
+use crate::memory::Heap;
+use crate::value::Value;
 
+// the heap is needed to show the names and constants that instructions refer to
 #[allow(dead_code)]
-pub fn disassemble_chunk(chunk: Chunk, name: String) -> () {
+pub fn disassemble_chunk(chunk: &Chunk, name: String, heap: &Heap) -> () {
     println!("== {} ==\n", name);
-    let list = chunk.code.iter();
 
-    for (idx, _codeline) in list.enumerate() {
-        disassemble_instruction(&chunk, idx);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(chunk, offset, heap);
     }
 }
 
-fn disassemble_instruction(chunk: &Chunk, offset: usize) -> () {
-    let code = &chunk.code[offset];
+// prints the instruction at `offset` and returns the offset of the next one
+fn disassemble_instruction(chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
     print!("{:04} ", offset);
-    let instruction = &code.code;
-    let line = code.line;
-    if offset > 0 && line == chunk.code[offset-1].line {
+    let line = chunk.get_line(offset);
+    if offset > 0 && line == chunk.get_line(offset - 1) {
         print!("   | ");
     } else {
         print!("{:04} ", line);
-    } 
+    }
+
+    let byte = chunk.code[offset];
+    let instruction = match OpCode::from_byte(byte) {
+        Some(op) => op,
+        None => {
+            println!("Unknown opcode {}", byte);
+            return offset + 1;
+        }
+    };
+
     match instruction {
-        OpCode::Return => simple_instruction("OP_RETURN"),
-        OpCode::Constant(constant) => constant_instruction("OP_CONSTANT", constant),
-        OpCode::DefineGlobal(val) => global_instruction("OP_DEFINE_GLOBAL", val),
-        OpCode::GetGlobal(val) => global_instruction("OP_GET_GLOBAL", val),
-        OpCode::SetGlobal(val) => global_instruction("OP_SET_GLOBAL", val),
-        OpCode::GetLocal(val) => local_instruction("OP_GET_LOCAL", val),
-        OpCode::SetLocal(val) => local_instruction("OP_SET_LOCAL", val),
-        OpCode::JumpIfFalse(val) => local_instruction("OP_JUMP_IF_FALSE", val),
-        OpCode::Jump(val) => local_instruction("OP_JUMP", val),
-        OpCode::Loop(val) => local_instruction("OP_LOOP", val),
-        OpCode::Negate => simple_instruction("OP_NEGATE"),
-        OpCode::Add => simple_instruction("OP_ADD"),
-        OpCode::Subtract => simple_instruction("OP_SUBTRACT"),
-        OpCode::Multiply => simple_instruction("OP_MULTIPLY"),
-        OpCode::Divide => simple_instruction("OP_DIVIDE"),
-        OpCode::Nil => simple_instruction("OP_NIL"),
-        OpCode::True => simple_instruction("OP_TRUE"),
-        OpCode::False => simple_instruction("OP_FALSE"),
-        OpCode::Not => simple_instruction("OP_NOT"),
-        OpCode::Equal => simple_instruction("OP_EQUAL"),
-        OpCode::Greater => simple_instruction("OP_GREATER"),
-        OpCode::Less => simple_instruction("OP_LESS"),
-        OpCode::Print => simple_instruction("OP_PRINT"),
-        OpCode::Pop => simple_instruction("OP_POP"),
+        OpCode::Return => simple_instruction("OP_RETURN", offset),
+        OpCode::Constant => constant_instruction("OP_CONSTANT", chunk, offset, heap),
+        OpCode::ConstantLong => constant_instruction("OP_CONSTANT_LONG", chunk, offset, heap),
+        OpCode::DefineGlobal => constant_instruction("OP_DEFINE_GLOBAL", chunk, offset, heap),
+        OpCode::DefineGlobalLong => constant_instruction("OP_DEFINE_GLOBAL_LONG", chunk, offset, heap),
+        OpCode::GetGlobal => constant_instruction("OP_GET_GLOBAL", chunk, offset, heap),
+        OpCode::GetGlobalLong => constant_instruction("OP_GET_GLOBAL_LONG", chunk, offset, heap),
+        OpCode::SetGlobal => constant_instruction("OP_SET_GLOBAL", chunk, offset, heap),
+        OpCode::SetGlobalLong => constant_instruction("OP_SET_GLOBAL_LONG", chunk, offset, heap),
+        OpCode::GetLocal => byte_instruction("OP_GET_LOCAL", chunk, offset),
+        OpCode::SetLocal => byte_instruction("OP_SET_LOCAL", chunk, offset),
+        OpCode::JumpIfFalse => jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset),
+        OpCode::Jump => jump_instruction("OP_JUMP", 1, chunk, offset),
+        OpCode::Loop => jump_instruction("OP_LOOP", -1, chunk, offset),
+        OpCode::Call => byte_instruction("OP_CALL", chunk, offset),
+        OpCode::Closure => closure_instruction("OP_CLOSURE", chunk, offset, heap),
+        OpCode::ClosureLong => closure_instruction("OP_CLOSURE_LONG", chunk, offset, heap),
+        OpCode::GetUpvalue => byte_instruction("OP_GET_UPVALUE", chunk, offset),
+        OpCode::SetUpvalue => byte_instruction("OP_SET_UPVALUE", chunk, offset),
+        OpCode::CloseUpvalue => simple_instruction("OP_CLOSE_UPVALUE", offset),
+        OpCode::Class => constant_instruction("OP_CLASS", chunk, offset, heap),
+        OpCode::ClassLong => constant_instruction("OP_CLASS_LONG", chunk, offset, heap),
+        OpCode::Method => constant_instruction("OP_METHOD", chunk, offset, heap),
+        OpCode::MethodLong => constant_instruction("OP_METHOD_LONG", chunk, offset, heap),
+        OpCode::GetProperty => constant_instruction("OP_GET_PROPERTY", chunk, offset, heap),
+        OpCode::GetPropertyLong => constant_instruction("OP_GET_PROPERTY_LONG", chunk, offset, heap),
+        OpCode::SetProperty => constant_instruction("OP_SET_PROPERTY", chunk, offset, heap),
+        OpCode::SetPropertyLong => constant_instruction("OP_SET_PROPERTY_LONG", chunk, offset, heap),
+        OpCode::Invoke => invoke_instruction("OP_INVOKE", chunk, offset, heap),
+        OpCode::InvokeLong => invoke_instruction("OP_INVOKE_LONG", chunk, offset, heap),
+        OpCode::Inherit => simple_instruction("OP_INHERIT", offset),
+        OpCode::GetSuper => constant_instruction("OP_GET_SUPER", chunk, offset, heap),
+        OpCode::GetSuperLong => constant_instruction("OP_GET_SUPER_LONG", chunk, offset, heap),
+        OpCode::SuperInvoke => invoke_instruction("OP_SUPER_INVOKE", chunk, offset, heap),
+        OpCode::SuperInvokeLong => invoke_instruction("OP_SUPER_INVOKE_LONG", chunk, offset, heap),
+        OpCode::Negate => simple_instruction("OP_NEGATE", offset),
+        OpCode::Add => simple_instruction("OP_ADD", offset),
+        OpCode::Subtract => simple_instruction("OP_SUBTRACT", offset),
+        OpCode::Multiply => simple_instruction("OP_MULTIPLY", offset),
+        OpCode::Divide => simple_instruction("OP_DIVIDE", offset),
+        OpCode::Modulo => simple_instruction("OP_MODULO", offset),
+        OpCode::Power => simple_instruction("OP_POWER", offset),
+        OpCode::BitAnd => simple_instruction("OP_BIT_AND", offset),
+        OpCode::BitOr => simple_instruction("OP_BIT_OR", offset),
+        OpCode::BitXor => simple_instruction("OP_BIT_XOR", offset),
+        OpCode::BitNot => simple_instruction("OP_BIT_NOT", offset),
+        OpCode::ShiftLeft => simple_instruction("OP_SHIFT_LEFT", offset),
+        OpCode::ShiftRight => simple_instruction("OP_SHIFT_RIGHT", offset),
+        OpCode::Nil => simple_instruction("OP_NIL", offset),
+        OpCode::True => simple_instruction("OP_TRUE", offset),
+        OpCode::False => simple_instruction("OP_FALSE", offset),
+        OpCode::Not => simple_instruction("OP_NOT", offset),
+        OpCode::Equal => simple_instruction("OP_EQUAL", offset),
+        OpCode::Greater => simple_instruction("OP_GREATER", offset),
+        OpCode::Less => simple_instruction("OP_LESS", offset),
+        OpCode::Print => simple_instruction("OP_PRINT", offset),
+        OpCode::Pop => simple_instruction("OP_POP", offset),
+        OpCode::BuildList => byte_instruction("OP_BUILD_LIST", chunk, offset),
+        OpCode::BuildMap => byte_instruction("OP_BUILD_MAP", chunk, offset),
+        OpCode::GetIndex => simple_instruction("OP_GET_INDEX", offset),
+        OpCode::SetIndex => simple_instruction("OP_SET_INDEX", offset),
+        OpCode::Stringify => simple_instruction("OP_STRINGIFY", offset),
+        OpCode::Dup => byte_instruction("OP_DUP", chunk, offset),
+        OpCode::Bury => byte_instruction("OP_BURY", chunk, offset),
+    }
+}
+
+// the constant index operand of the instruction at `offset`, and how many bytes it took
+fn read_index(chunk: &Chunk, offset: usize) -> (usize, usize) {
+    let long = OpCode::from_byte(chunk.code[offset]).is_some_and(|op| op.is_long());
+    if long {
+        (chunk.read_u24(offset + 1), 3)
+    } else {
+        (chunk.code[offset + 1] as usize, 1)
     }
 }
 
-fn simple_instruction(name: &str) -> () {
+fn simple_instruction(name: &str, offset: usize) -> usize {
     println!("{}", name);
+    offset + 1
 }
 
-fn global_instruction(name: &str, constant: &String) -> () {
-    println!("{} {}",name, constant);
+fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    println!("{:<16} {:4}", name, chunk.code[offset + 1]);
+    offset + 2
 }
 
-fn constant_instruction(name: &str, constant: &Value) -> () {
-    println!("{} {}",name, constant);
-} 
+fn jump_instruction(name: &str, sign: i64, chunk: &Chunk, offset: usize) -> usize {
+    let jump = chunk.read_u16(offset + 1) as i64;
+    let target = offset as i64 + 3 + sign * jump;
+    println!("{:<16} {:4} -> {}", name, offset, target);
+    offset + 3
+}
+
+fn constant_instruction(name: &str, chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
+    let (index, width) = read_index(chunk, offset);
+    println!("{:<16} {:4} '{}'", name, index, heap.format_value(&chunk.constants[index]));
+    offset + 1 + width
+}
 
-fn local_instruction(name: &str, constant: &usize) -> () {
-    println!("{} {}",name, constant);
+fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
+    let (index, width) = read_index(chunk, offset);
+    let arg_count = chunk.code[offset + 1 + width];
+    println!("{:<16} ({} args) {:4} '{}'", name, arg_count, index, heap.format_value(&chunk.constants[index]));
+    offset + 2 + width
 }
 
+fn closure_instruction(name: &str, chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
+    let (index, width) = read_index(chunk, offset);
+    let function = chunk.constants[index];
+    println!("{:<16} {:4} {}", name, index, heap.format_value(&function));
+
+    let mut offset = offset + 1 + width;
+    let upvalue_count = heap.function(Value::as_function(function)).upvalue_count;
+    for _ in 0..upvalue_count {
+        let kind = if chunk.code[offset] == 1 { "local" } else { "upvalue" };
+        println!("{:04}    |                     {} {}", offset, kind, chunk.code[offset + 1]);
+        offset += 2;
+    }
+    offset
+}
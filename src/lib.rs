@@ -0,0 +1,36 @@
+// a bytecode virtual machine for Lox, from Crafting Interpreters
+//
+// embedding looks like
+//
+//     let mut vm = lox_rust::VM::new();
+//     match vm.interpret_named(&source, "main.lox") {
+//         lox_rust::InterpretResult::Ok => (),
+//         lox_rust::InterpretResult::CompileError(diagnostics) => ...,
+//         lox_rust::InterpretResult::RuntimeError(diagnostic) => ...,
+//     }
+//
+// everything below the re-exports is an implementation detail and can change freely
+
+// the codebase spells out `-> ()` on purpose
+#![allow(clippy::unused_unit)]
+
+mod compiler;
+mod chunk;
+mod diagnostic;
+mod disassembler;
+mod memory;
+mod natives;
+mod object;
+mod scanner;
+mod serialize;
+mod streams;
+mod unicode;
+mod value;
+mod vm;
+
+pub use diagnostic::{Diagnostic, ErrorCode, Severity, Span, TraceFrame};
+pub use object::{Map, MapKey, NativeFn};
+pub use serialize::{is_bytecode, FORMAT_VERSION};
+pub use streams::SharedBuffer;
+pub use value::Value;
+pub use vm::{InterpretResult, Root, VM};
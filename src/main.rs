@@ -1,60 +1,98 @@
-#![feature(const_fn)] 
+// the codebase spells out `-> ()` on purpose
+#![allow(clippy::unused_unit)]
 use std::env;
 use std::io::{Read, Write};
 
-mod compiler;
-mod chunk;
-mod disassembler;
-mod scanner;
-mod value;
-mod vm;
+use lox_rust::{InterpretResult, VM};
 
 fn main() {
-    let mut vm = vm::VM::new();
+    // VM::new talks to the process's stdin, stdout and stderr
+    let mut vm = VM::new();
+    // debugging aids for the garbage collector
+    vm.set_gc_stress(env::var_os("LOX_GC_STRESS").is_some());
+    vm.set_gc_log(env::var_os("LOX_GC_LOG").is_some());
     let args: Vec<String> = env::args().collect();
     match args.len() {
         1 => repl(&mut vm),
-        2 => match run_file(&args[1], &mut vm) {
-            Err(_) => {
-                eprintln!("Invalid file at {}", args[1]);
-                std::process::exit(74);
-            },
-            _ => (),
+        2 => if run_file(&args[1], &mut vm).is_err() {
+            eprintln!("Invalid file at {}", args[1]);
+            std::process::exit(74);
+        },
+        5 if args[1] == "compile" && args[3] == "-o" => if compile_file(&args[2], &args[4], &mut vm).is_err() {
+            eprintln!("Could not compile {} to {}", args[2], args[4]);
+            std::process::exit(74);
         },
         _ => {
             eprintln!("Usage: clox [path]");
+            eprintln!("       clox compile [path] -o [output.loxc]");
             std::process::exit(64);
         }
     }
 }
 
-fn repl(machine: &mut vm::VM) -> () {
+fn repl(machine: &mut VM) -> () {
    loop {
         print!("> ");
         std::io::stdout().flush().unwrap();
 
-        let mut input = String::new();
-        let expression = std::io::stdin().read_line(&mut input);
-        match expression {
-            Ok(_) => (),
+        // read through the VM, so scripts calling readLine share its buffered input
+        let input = match machine.read_line() {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                println!();
+                break;
+            },
             Err(_) => {
                 println!("Error!");
-               break;
+                break;
             }
-        }
-        machine.interpret(&input);
+        };
+        let result = machine.interpret_named(&input, "<repl>");
+        let _ = machine.report(&result, Some(&input));
     }
 }
 
-fn run_file(path: &String, machine: &mut vm::VM) -> std::io::Result<()> {
-    let mut buffer = String::new();
+fn run_file(path: &str, machine: &mut VM) -> std::io::Result<()> {
+    let mut buffer = Vec::new();
     let mut f = std::fs::File::open(path)?;
-    f.read_to_string(&mut buffer)?;
-    let result : vm::InterpretResult = machine.interpret(&buffer);
+    f.read_to_end(&mut buffer)?;
+
+    // compiled files don't carry their source, so their errors come without a snippet
+    let mut source = None;
+    // precompiled files are recognised by their header, whatever they're named
+    let result : InterpretResult = if lox_rust::is_bytecode(&buffer) {
+        machine.interpret_bytecode(&buffer)
+    } else {
+        let text = String::from_utf8(buffer)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        let result = machine.interpret_named(&text, path);
+        source = Some(text);
+        result
+    };
 
     match result {
-        vm::InterpretResult::CompileError => std::process::exit(65),
-        vm::InterpretResult::RuntimeError => std::process::exit(70),
+        InterpretResult::CompileError(_) => {
+            let _ = machine.report(&result, source.as_deref());
+            std::process::exit(65)
+        },
+        InterpretResult::RuntimeError(_) => {
+            let _ = machine.report(&result, source.as_deref());
+            std::process::exit(70)
+        },
         _ => Ok(()),
     }
 }
+
+fn compile_file(path: &str, output: &str, machine: &mut VM) -> std::io::Result<()> {
+    let mut source = String::new();
+    let mut f = std::fs::File::open(path)?;
+    f.read_to_string(&mut source)?;
+
+    match machine.compile_to_bytecode(&source, path) {
+        Ok(bytes) => std::fs::write(output, bytes),
+        Err(diagnostics) => {
+            let _ = machine.report(&InterpretResult::CompileError(diagnostics), Some(&source));
+            std::process::exit(65)
+        },
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+
+use crate::object::{BoundMethod, Class, Closure, Function, Instance, Map, Native, Obj, ObjRef, Upvalue};
+use crate::value::{Value, ValueType};
+
+// don't bother collecting until the heap has grown to at least this size
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+struct HeapEntry {
+    obj: Obj,
+    size: usize,
+    marked: bool,
+}
+
+// owns every object the VM allocates
+// objects are reclaimed with a tracing mark-and-sweep collector; the VM marks
+// its roots, then trace_references and sweep do the rest
+pub struct Heap {
+    objects: Vec<Option<HeapEntry>>,
+    // slots freed by the last sweep, reused before the vector grows
+    free_slots: Vec<usize>,
+    // objects that are marked but haven't had their references traced yet
+    gray_stack: Vec<ObjRef>,
+    // every live string, keyed by its contents, so equal strings share one handle
+    // the table doesn't keep its strings alive, sweep drops entries for dead ones
+    strings: HashMap<String, ObjRef>,
+
+    bytes_allocated: usize,
+    next_gc: usize,
+
+    // collect before every instruction, to shake out missing roots
+    pub stress_gc: bool,
+    // report every collection on stderr
+    pub log_gc: bool,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            free_slots: Vec::new(),
+            gray_stack: Vec::new(),
+            strings: HashMap::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            stress_gc: false,
+            log_gc: false,
+        }
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        self.bytes_allocated += size;
+
+        let entry = HeapEntry {
+            obj,
+            size,
+            marked: false,
+        };
+
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.objects[slot] = Some(entry);
+                ObjRef(slot)
+            },
+            None => {
+                self.objects.push(Some(entry));
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    // all strings go through here, so two handles are equal exactly when their strings are
+    pub fn intern(&mut self, string: &str) -> ObjRef {
+        if let Some(handle) = self.strings.get(string) {
+            return *handle;
+        }
+
+        let handle = self.alloc(Obj::String(string.to_string()));
+        self.strings.insert(string.to_string(), handle);
+        handle
+    }
+
+    // objects that grow after they're allocated, lists, maps, instances and classes,
+    // are measured again after each write so the growth counts toward the next collection
+    pub fn remeasure(&mut self, handle: ObjRef) -> () {
+        if let Some(entry) = &mut self.objects[handle.0] {
+            let size = entry.obj.size();
+            self.bytes_allocated = self.bytes_allocated - entry.size + size;
+            entry.size = size;
+        }
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn get(&self, handle: ObjRef) -> &Obj {
+        match &self.objects[handle.0] {
+            Some(entry) => &entry.obj,
+            None => panic!("Heap::get called with a freed handle {}", handle),
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: ObjRef) -> &mut Obj {
+        match &mut self.objects[handle.0] {
+            Some(entry) => &mut entry.obj,
+            None => panic!("Heap::get_mut called with a freed handle {}", handle),
+        }
+    }
+
+    pub fn string(&self, handle: ObjRef) -> &String {
+        match self.get(handle) {
+            Obj::String(string) => string,
+            _ => panic!("Heap::string should never be called on a non-string"),
+        }
+    }
+
+    pub fn function(&self, handle: ObjRef) -> &Function {
+        match self.get(handle) {
+            Obj::Function(function) => function,
+            _ => panic!("Heap::function should never be called on a non-function"),
+        }
+    }
+
+    pub fn native(&self, handle: ObjRef) -> &Native {
+        match self.get(handle) {
+            Obj::Native(native) => native,
+            _ => panic!("Heap::native should never be called on a non-native"),
+        }
+    }
+
+    pub fn closure(&self, handle: ObjRef) -> &Closure {
+        match self.get(handle) {
+            Obj::Closure(closure) => closure,
+            _ => panic!("Heap::closure should never be called on a non-closure"),
+        }
+    }
+
+    pub fn upvalue(&self, handle: ObjRef) -> &Upvalue {
+        match self.get(handle) {
+            Obj::Upvalue(upvalue) => upvalue,
+            _ => panic!("Heap::upvalue should never be called on a non-upvalue"),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, handle: ObjRef) -> &mut Upvalue {
+        match self.get_mut(handle) {
+            Obj::Upvalue(upvalue) => upvalue,
+            _ => panic!("Heap::upvalue_mut should never be called on a non-upvalue"),
+        }
+    }
+
+    pub fn class(&self, handle: ObjRef) -> &Class {
+        match self.get(handle) {
+            Obj::Class(class) => class,
+            _ => panic!("Heap::class should never be called on a non-class"),
+        }
+    }
+
+    pub fn class_mut(&mut self, handle: ObjRef) -> &mut Class {
+        match self.get_mut(handle) {
+            Obj::Class(class) => class,
+            _ => panic!("Heap::class_mut should never be called on a non-class"),
+        }
+    }
+
+    pub fn instance(&self, handle: ObjRef) -> &Instance {
+        match self.get(handle) {
+            Obj::Instance(instance) => instance,
+            _ => panic!("Heap::instance should never be called on a non-instance"),
+        }
+    }
+
+    pub fn instance_mut(&mut self, handle: ObjRef) -> &mut Instance {
+        match self.get_mut(handle) {
+            Obj::Instance(instance) => instance,
+            _ => panic!("Heap::instance_mut should never be called on a non-instance"),
+        }
+    }
+
+    pub fn bound_method(&self, handle: ObjRef) -> &BoundMethod {
+        match self.get(handle) {
+            Obj::BoundMethod(bound) => bound,
+            _ => panic!("Heap::bound_method should never be called on a non-bound-method"),
+        }
+    }
+
+    pub fn map(&self, handle: ObjRef) -> &Map {
+        match self.get(handle) {
+            Obj::Map(map) => map,
+            _ => panic!("Heap::map should never be called on a non-map"),
+        }
+    }
+
+    pub fn map_mut(&mut self, handle: ObjRef) -> &mut Map {
+        match self.get_mut(handle) {
+            Obj::Map(map) => map,
+            _ => panic!("Heap::map_mut should never be called on a non-map"),
+        }
+    }
+
+    pub fn list(&self, handle: ObjRef) -> &Vec<Value> {
+        match self.get(handle) {
+            Obj::List(items) => items,
+            _ => panic!("Heap::list should never be called on a non-list"),
+        }
+    }
+
+    pub fn list_mut(&mut self, handle: ObjRef) -> &mut Vec<Value> {
+        match self.get_mut(handle) {
+            Obj::List(items) => items,
+            _ => panic!("Heap::list_mut should never be called on a non-list"),
+        }
+    }
+
+    // renders a value the way `print` shows it
+    pub fn format_value(&self, value: &Value) -> String {
+        self.format_nested(value, &mut Vec::new())
+    }
+
+    // `enclosing` holds the collections being printed around this value,
+    // so a collection containing itself prints as [...] or {...} instead of recursing forever
+    fn format_nested(&self, value: &Value, enclosing: &mut Vec<ObjRef>) -> String {
+        match value.value_type {
+            ValueType::List(handle) => {
+                if enclosing.contains(&handle) {
+                    return "[...]".to_string();
+                }
+                enclosing.push(handle);
+                let items: Vec<String> = self.list(handle).iter()
+                    .map(|item| self.format_nested(item, enclosing))
+                    .collect();
+                enclosing.pop();
+                format!("[{}]", items.join(", "))
+            },
+            ValueType::Map(handle) => {
+                if enclosing.contains(&handle) {
+                    return "{...}".to_string();
+                }
+                enclosing.push(handle);
+                let entries: Vec<String> = self.map(handle).iter()
+                    .map(|(key, value)| format!("{}: {}", self.format_nested(&key.to_value(), enclosing), self.format_nested(value, enclosing)))
+                    .collect();
+                enclosing.pop();
+                format!("{{{}}}", entries.join(", "))
+            },
+            ValueType::String(handle) => format!("\"{}\"", self.string(handle)),
+            ValueType::Function(handle) => match self.function(handle).name {
+                Some(name) => format!("<fn {}>", self.string(name)),
+                None => "<script>".to_string(),
+            },
+            ValueType::Native(handle) => format!("{}", self.native(handle)),
+            ValueType::Closure(handle) => self.format_value(&Value::function_val(self.closure(handle).function)),
+            ValueType::Class(handle) => self.string(self.class(handle).name).clone(),
+            ValueType::Instance(handle) => {
+                let class = self.instance(handle).class;
+                format!("{} instance", self.string(self.class(class).name))
+            },
+            ValueType::BoundMethod(handle) => self.format_value(&Value::closure_val(self.bound_method(handle).method)),
+            _ => format!("{}", value),
+        }
+    }
+
+    /*
+     * mark and sweep
+     */
+
+    pub fn mark_value(&mut self, value: &Value) -> () {
+        if let Some(handle) = Value::as_obj_ref(value) {
+            self.mark_object(handle);
+        }
+    }
+
+    pub fn mark_object(&mut self, handle: ObjRef) -> () {
+        if let Some(entry) = &mut self.objects[handle.0] {
+            if entry.marked {
+                return;
+            }
+            entry.marked = true;
+            self.gray_stack.push(handle);
+        }
+    }
+
+    // keeps blackening gray objects until everything reachable from the roots is marked
+    pub fn trace_references(&mut self) -> () {
+        while let Some(handle) = self.gray_stack.pop() {
+            self.blacken_object(handle);
+        }
+    }
+
+    fn blacken_object(&mut self, handle: ObjRef) -> () {
+        // gather the children first, marking needs the heap mutably
+        let mut children: Vec<ObjRef> = Vec::new();
+        let push_value = |children: &mut Vec<ObjRef>, value: &Value| {
+            if let Some(child) = Value::as_obj_ref(value) {
+                children.push(child);
+            }
+        };
+
+        match self.get(handle) {
+            Obj::String(_) | Obj::Native(_) => (),
+            Obj::Function(function) => {
+                children.extend(function.name.iter());
+                children.push(function.file);
+                for constant in function.chunk.constants.iter() {
+                    push_value(&mut children, constant);
+                }
+            },
+            Obj::Closure(closure) => {
+                children.push(closure.function);
+                children.extend(closure.upvalues.iter());
+            },
+            Obj::Upvalue(upvalue) => {
+                if let Upvalue::Closed(value) = upvalue {
+                    push_value(&mut children, value);
+                }
+            },
+            Obj::Class(class) => {
+                children.push(class.name);
+                for (name, method) in class.methods.iter() {
+                    children.push(*name);
+                    push_value(&mut children, method);
+                }
+            },
+            Obj::Instance(instance) => {
+                children.push(instance.class);
+                for (name, field) in instance.fields.iter() {
+                    children.push(*name);
+                    push_value(&mut children, field);
+                }
+            },
+            Obj::BoundMethod(bound) => {
+                push_value(&mut children, &bound.receiver);
+                children.push(bound.method);
+            },
+            Obj::List(items) => {
+                for item in items.iter() {
+                    push_value(&mut children, item);
+                }
+            },
+            Obj::Map(map) => {
+                for (key, value) in map.iter() {
+                    push_value(&mut children, &key.to_value());
+                    push_value(&mut children, value);
+                }
+            },
+        }
+
+        for child in children {
+            self.mark_object(child);
+        }
+    }
+
+    // frees every object that wasn't marked, and clears the marks on the survivors
+    pub fn sweep(&mut self) -> () {
+        // the intern table is weak, forget strings nothing else is holding on to
+        let objects = &self.objects;
+        self.strings.retain(|_, handle| match &objects[handle.0] {
+            Some(entry) => entry.marked,
+            None => false,
+        });
+
+        for (slot, object) in self.objects.iter_mut().enumerate() {
+            let reachable = match object {
+                Some(entry) if entry.marked => {
+                    entry.marked = false;
+                    true
+                },
+                Some(_) => false,
+                None => continue,
+            };
+
+            if !reachable {
+                self.bytes_allocated -= object.as_ref().unwrap().size;
+                *object = None;
+                self.free_slots.push(slot);
+            }
+        }
+
+        self.next_gc = std::cmp::max(self.bytes_allocated * GC_HEAP_GROW_FACTOR, INITIAL_GC_THRESHOLD);
+    }
+
+    pub fn next_gc(&self) -> usize {
+        self.next_gc
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::object::MapKey;
+use crate::value::{Value, ValueType};
+use crate::vm::VM;
+
+// the natives every VM starts out with
+pub fn define_builtins(vm: &mut VM) -> () {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("readLine", 0, read_line);
+    vm.define_native("len", 1, len);
+    vm.define_native("push", 2, push);
+    vm.define_native("pop", 1, pop);
+    vm.define_native("insert", 3, insert);
+    vm.define_native("remove", 2, remove);
+    vm.define_native("has", 2, has);
+    vm.define_native("keys", 1, keys);
+    vm.define_native("int", 1, int);
+    vm.define_native("float", 1, float);
+}
+
+fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => Ok(Value::number_val(elapsed.as_secs_f64())),
+        Err(_) => Err("System clock is set before the unix epoch.".to_string()),
+    }
+}
+
+// a line from the VM's input, or nil at the end of it
+fn read_line(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    match vm.read_line() {
+        Ok(Some(line)) => Ok(vm.new_string(&line)),
+        Ok(None) => Ok(Value::nil_val()),
+        Err(error) => Err(format!("Could not read input: {}.", error)),
+    }
+}
+
+// the number of items in a list, entries in a map, or characters in a string
+fn len(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if let Some(items) = vm.as_list(args[0]) {
+        Ok(Value::int_val(items.len() as i64))
+    } else if let Some(map) = vm.as_map(args[0]) {
+        Ok(Value::int_val(map.len() as i64))
+    } else if let Some(string) = vm.as_str(args[0]) {
+        Ok(Value::int_val(string.chars().count() as i64))
+    } else {
+        Err("len() expects a list, a map or a string.".to_string())
+    }
+}
+
+fn list_arg<'a>(vm: &'a mut VM, value: Value, name: &str) -> Result<&'a mut Vec<Value>, String> {
+    vm.as_list_mut(value).ok_or(format!("{}() expects a list.", name))
+}
+
+// appends to the end of a list
+fn push(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    list_arg(vm, args[0], "push")?.push(args[1]);
+    Ok(Value::nil_val())
+}
+
+// removes and returns the last item of a list
+fn pop(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match list_arg(vm, args[0], "pop")?.pop() {
+        Some(item) => Ok(item),
+        None => Err("Cannot pop from an empty list.".to_string()),
+    }
+}
+
+// inserts before the item at the index, an index of len() appends
+fn insert(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let items = list_arg(vm, args[0], "insert")?;
+    let index = Value::as_index(&args[1], items.len() + 1)?;
+    items.insert(index, args[2]);
+    Ok(Value::nil_val())
+}
+
+// removes and returns the item at the index of a list, shifting the rest down,
+// or the value under a key of a map, nil if the key wasn't there
+fn remove(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if let Some(map) = vm.as_map_mut(args[0]) {
+        let key = MapKey::from_value(&args[1])?;
+        return Ok(map.remove(&key).unwrap_or_else(Value::nil_val));
+    }
+
+    let items = vm.as_list_mut(args[0]).ok_or("remove() expects a list or a map.")?;
+    let index = Value::as_index(&args[1], items.len())?;
+    Ok(items.remove(index))
+}
+
+// whether a map has an entry for the key
+fn has(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = vm.as_map(args[0]).ok_or("has() expects a map.")?;
+    let key = MapKey::from_value(&args[1])?;
+    Ok(Value::bool_val(map.contains(&key)))
+}
+
+// a list of a map's keys, in the order they were first added
+fn keys(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = vm.as_map(args[0]).ok_or("keys() expects a map.")?;
+    let keys = map.iter().map(|(key, _)| key.to_value()).collect();
+    Ok(vm.new_list(keys))
+}
+
+// a number as an int, floats are truncated toward zero
+fn int(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args[0].value_type {
+        ValueType::Int(_) => Ok(args[0]),
+        ValueType::Number(number) => match Value::exact_int(number.trunc()) {
+            Some(int) => Ok(Value::int_val(int)),
+            None => Err(format!("Cannot convert {} to an int.", number)),
+        },
+        _ => Err("int() expects a number.".to_string()),
+    }
+}
+
+// a number as a float, ints beyond 2^53 are rounded to the nearest float
+fn float(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match Value::to_float(&args[0]) {
+        Some(number) => Ok(Value::number_val(number)),
+        None => Err("float() expects a number.".to_string()),
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, LocationRun};
+use crate::value::{Value, ValueType};
+use crate::vm::VM;
+
+// a handle to an object living in the VM's heap
+// it's only an index, so it's cheap to copy around and never dangles on its own;
+// the garbage collector is what decides when the slot behind it gets reused
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ObjRef(pub usize);
+
+impl fmt::Display for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+// everything that lives on the heap
+pub enum Obj {
+    String(String),
+    Function(Function),
+    Native(Native),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    List(Vec<Value>),
+    Map(Map),
+}
+
+impl Obj {
+    // rough count of the bytes this object keeps alive, used to pace the collector
+    pub fn size(&self) -> usize {
+        let owned = match self {
+            Obj::String(string) => string.capacity(),
+            Obj::Function(function) => function.chunk.code.capacity()
+                + function.chunk.constants.capacity() * mem::size_of::<Value>()
+                + function.chunk.locations.capacity() * mem::size_of::<LocationRun>(),
+            Obj::Native(native) => native.name.capacity(),
+            Obj::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<ObjRef>(),
+            Obj::Upvalue(_) => 0,
+            Obj::Class(class) => class.methods.capacity() * mem::size_of::<(ObjRef, Value)>(),
+            Obj::Instance(instance) => instance.fields.capacity() * mem::size_of::<(ObjRef, Value)>(),
+            Obj::BoundMethod(_) => 0,
+            Obj::List(items) => items.capacity() * mem::size_of::<Value>(),
+            Obj::Map(map) => map.entries.capacity() * mem::size_of::<(MapKey, Value)>()
+                + map.index.capacity() * mem::size_of::<(MapKey, usize)>(),
+        };
+        mem::size_of::<Obj>() + owned
+    }
+}
+
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<ObjRef>,
+    // the script the function was compiled from, for stack traces
+    pub file: ObjRef,
+}
+
+impl Function {
+    pub fn new(name: Option<ObjRef>, file: ObjRef) -> Function {
+        Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new_chunk(),
+            name,
+            file,
+        }
+    }
+}
+
+// a function implemented in rust and handed to scripts by the host
+// returning Err raises a runtime error with that message
+// it's a closure so it can hold on to host state, like a logger or a counter
+pub type NativeFn = Rc<dyn Fn(&mut VM, &[Value]) -> Result<Value, String>>;
+
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl fmt::Display for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+// a captured variable
+// while the variable's frame is live it's Open and points at the stack slot
+// once the slot is popped the value is hoisted into the upvalue and it becomes Closed
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct Closure {
+    pub function: ObjRef,
+    // every handle in here points at an Obj::Upvalue
+    pub upvalues: Vec<ObjRef>,
+}
+
+impl Closure {
+    pub fn new(function: ObjRef) -> Closure {
+        Closure {
+            function,
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+pub struct Class {
+    pub name: ObjRef,
+    // every value in here is a closure
+    pub methods: HashMap<ObjRef, Value>,
+}
+
+impl Class {
+    pub fn new(name: ObjRef) -> Class {
+        Class {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+impl Instance {
+    pub fn new(class: ObjRef) -> Instance {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+// a method pulled off an instance, remembering which instance `this` should be
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// the values that can be map keys, hashed so that two keys collide exactly
+// when Value::values_equal says the values are equal
+// strings are interned, so comparing handles compares contents
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    // whole floats are stored as the equal Int, so `1` and `1.0` are the same key
+    Int(i64),
+    // the bits of a float with a fractional part, or too big to be an Int
+    Number(u64),
+    String(ObjRef),
+}
+
+impl MapKey {
+    pub fn from_value(value: &Value) -> Result<MapKey, String> {
+        match value.value_type {
+            ValueType::Nil => Ok(MapKey::Nil),
+            ValueType::Bool(b) => Ok(MapKey::Bool(b)),
+            // NaN never equals itself, a NaN key could be stored but never found again
+            ValueType::Number(n) if n.is_nan() => Err("Map keys cannot be NaN.".to_string()),
+            ValueType::Number(n) => Ok(match Value::exact_int(n) {
+                Some(int) => MapKey::Int(int),
+                None => MapKey::Number(n.to_bits()),
+            }),
+            ValueType::Int(int) => Ok(MapKey::Int(int)),
+            ValueType::String(handle) => Ok(MapKey::String(handle)),
+            _ => Err("Map keys must be strings, numbers, booleans or nil.".to_string()),
+        }
+    }
+
+    pub fn to_value(self) -> Value {
+        match self {
+            MapKey::Nil => Value::nil_val(),
+            MapKey::Bool(b) => Value::bool_val(b),
+            MapKey::Int(int) => Value::int_val(int),
+            MapKey::Number(bits) => Value::number_val(f64::from_bits(bits)),
+            MapKey::String(handle) => Value::string_val(handle),
+        }
+    }
+}
+
+// a hash map that remembers insertion order, so printing and iterating are predictable
+#[derive(Default)]
+pub struct Map {
+    entries: Vec<(MapKey, Value)>,
+    // where each key's entry sits in `entries`
+    index: HashMap<MapKey, usize>,
+}
+
+impl Map {
+    pub fn new() -> Map {
+        Map::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<Value> {
+        self.index.get(key).map(|slot| self.entries[*slot].1)
+    }
+
+    pub fn contains(&self, key: &MapKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    // overwriting a key keeps its original position
+    pub fn insert(&mut self, key: MapKey, value: Value) -> () {
+        match self.index.get(&key) {
+            Some(slot) => self.entries[*slot].1 = value,
+            None => {
+                self.index.insert(key, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let slot = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(slot);
+        // everything after the removed entry moved down one
+        for (key, _) in self.entries[slot..].iter() {
+            *self.index.get_mut(key).unwrap() -= 1;
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(MapKey, Value)> {
+        self.entries.iter()
+    }
+}
@@ -1,13 +1,24 @@
+use crate::diagnostic::Span;
+use crate::unicode;
+
 pub struct Scanner {
     source: Vec<char>,
     start: usize,
     current: usize,
     line: i32,
+    // offset of the first character on the current line, for working out columns
+    line_start: usize,
+    // where the token being scanned began
+    start_line: i32,
+    start_column: usize,
     len: usize,
+    // one entry per `${` we're inside of, counting the braces opened within it
+    // so the `}` that ends the interpolation can be told apart from the others
+    interpolations: Vec<usize>,
 }
 
 impl Scanner {
-    pub fn new(source: &String) -> Scanner {
+    pub fn new(source: &str) -> Scanner {
         let chars: Vec<char> = source.chars().collect();
         let len: usize = chars.len();
 
@@ -16,13 +27,19 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
             len,
+            interpolations: Vec::new(),
         }
     }
 
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.current - self.line_start + 1;
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
         }
@@ -32,15 +49,71 @@ impl Scanner {
         match c {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            },
+            '}' => {
+                match self.interpolations.last_mut() {
+                    // the end of an interpolated expression, pick the string back up
+                    Some(0) => {
+                        self.interpolations.pop();
+                        self.string()
+                    },
+                    Some(depth) => {
+                        *depth -= 1;
+                        self.make_token(TokenType::RightBrace)
+                    },
+                    None => self.make_token(TokenType::RightBrace),
+                }
+            },
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
             ';' => self.make_token(TokenType::Semicolon),
+            ':' => self.make_token(TokenType::Colon),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
-            '/' => self.make_token(TokenType::Slash),
-            '*' => self.make_token(TokenType::Star),
+            '%' => self.make_token(TokenType::Percent),
+            '&' => self.make_token(TokenType::Ampersand),
+            '|' => self.make_token(TokenType::Pipe),
+            '^' => self.make_token(TokenType::Caret),
+            '~' => self.make_token(TokenType::Tilde),
+            '-' => {
+                if self.match_token('=') {
+                    self.make_token(TokenType::MinusEqual)
+                } else if self.match_token('-') {
+                    self.make_token(TokenType::MinusMinus)
+                } else {
+                    self.make_token(TokenType::Minus)
+                }
+            },
+            '+' => {
+                if self.match_token('=') {
+                    self.make_token(TokenType::PlusEqual)
+                } else if self.match_token('+') {
+                    self.make_token(TokenType::PlusPlus)
+                } else {
+                    self.make_token(TokenType::Plus)
+                }
+            },
+            '/' => {
+                if self.match_token('=') {
+                    self.make_token(TokenType::SlashEqual)
+                } else {
+                    self.make_token(TokenType::Slash)
+                }
+            },
+            '*' => {
+                if self.match_token('=') {
+                    self.make_token(TokenType::StarEqual)
+                } else if self.match_token('*') {
+                    self.make_token(TokenType::StarStar)
+                } else {
+                    self.make_token(TokenType::Star)
+                }
+            },
             '!' => {
                 if self.match_token('=') {
                     self.make_token(TokenType::BangEqual)
@@ -58,6 +131,8 @@ impl Scanner {
             '<' => {
                 if self.match_token('=') {
                     self.make_token(TokenType::LessEqual)
+                } else if self.match_token('<') {
+                    self.make_token(TokenType::LessLess)
                 } else {
                     self.make_token(TokenType::Less)
                 }
@@ -65,6 +140,8 @@ impl Scanner {
             '>' => {
                 if self.match_token('=') {
                     self.make_token(TokenType::GreaterEqual)
+                } else if self.match_token('>') {
+                    self.make_token(TokenType::GreaterGreater)
                 } else {
                     self.make_token(TokenType::Greater)
                 }
@@ -76,6 +153,16 @@ impl Scanner {
         }
     }
 
+    // the next `count` tokens, without consuming them
+    // for the few places the grammar needs to see past the parser's current token
+    pub fn peek_tokens(&mut self, count: usize) -> Vec<Token> {
+        // scanning through a `${` or `}` changes the interpolation stack, so it's put back too
+        let saved = (self.start, self.current, self.line, self.line_start, self.start_line, self.start_column, self.interpolations.clone());
+        let tokens = (0..count).map(|_| self.scan_token()).collect();
+        (self.start, self.current, self.line, self.line_start, self.start_line, self.start_column, self.interpolations) = saved;
+        tokens
+    }
+
     pub fn is_at_end(&self) -> bool {
         self.current >= self.len || self.source[self.current] == '\0'
     }
@@ -83,9 +170,11 @@ impl Scanner {
     fn make_token(&self, token_type: TokenType) -> Token {
         Token {
             token_type,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
             lexeme: self.source[self.start..self.current].iter().collect(),
             start: self.start,
+            length: self.current - self.start,
         }
     }
 
@@ -93,59 +182,131 @@ impl Scanner {
         Token {
             token_type: TokenType::Error,
             lexeme: message,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
             start: self.start,
+            length: self.current - self.start,
         }
     }
 
+    // scans up to the closing quote, or up to a `${`, which makes the token an
+    // Interpolation and leaves the expression inside for the following tokens
+    // escapes are only checked here, `unescape` turns them into characters
     fn string(&mut self) -> Token {
+        let mut bad_escape: Option<String> = None;
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.peek() == '$' && self.peek_next() == '{' {
+                self.advance();
+                self.advance();
+                if let Some(message) = bad_escape {
+                    return self.error_token(message);
+                }
+                self.interpolations.push(0);
+                return self.make_token(TokenType::Interpolation);
+            }
+
+            if self.advance() == '\\' {
+                if let Err(message) = self.escape() {
+                    bad_escape.get_or_insert(message);
+                }
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            self.error_token("Unterminated string.".to_string())
-        } else {
-            self.advance();
-            self.make_token(TokenType::TokenString)
+            return self.error_token("Unterminated string.".to_string());
+        }
+
+        self.advance();
+        match bad_escape {
+            Some(message) => self.error_token(message),
+            None => self.make_token(TokenType::TokenString),
+        }
+    }
+
+    // checks the escape sequence following a backslash
+    fn escape(&mut self) -> Result<(), String> {
+        if self.is_at_end() {
+            return Err("Unterminated string.".to_string());
+        }
+
+        match self.advance() {
+            'n' | 't' | 'r' | '"' | '\\' | '$' => Ok(()),
+            'u' => {
+                if self.peek() != '{' {
+                    return Err("Expect '{' after '\\u'.".to_string());
+                }
+                self.advance();
+
+                let mut digits = String::new();
+                while self.peek().is_ascii_hexdigit() {
+                    digits.push(self.advance());
+                }
+                if self.peek() != '}' {
+                    return Err("Unicode escape must be hex digits followed by '}'.".to_string());
+                }
+                self.advance();
+
+                match u32::from_str_radix(&digits, 16).ok().filter(|_| digits.len() <= 6).and_then(char::from_u32) {
+                    Some(_) => Ok(()),
+                    None => Err(format!("Invalid unicode escape '\\u{{{}}}'.", digits)),
+                }
+            },
+            ch => Err(format!("Invalid escape sequence '\\{}'.", ch)),
         }
     }
 
     fn is_digit(&self, ch: char) -> bool {
-        ch >= '0' && ch <= '9'
+        ch.is_ascii_digit()
     }
 
+    // scans anything shaped like a number: `12`, `1_000`, `0xff`, `0b1010`, `0o17` are Integers,
+    // `1.5` and `1.5e-3` are Numbers
+    // letters and underscores glued to it are taken too, so `0xfg` or `1__0` comes out as one
+    // token, `parse_integer` and `parse_number` decide whether the text is actually a valid literal
     fn number(&mut self) -> Token {
-        // capture all the digits we see
-        // if the thing right after is a period, and after that is more digits
-        // assume we're looking at a float, so capture whole thing
-        while self.is_digit(self.peek()) {
+        let first = self.source[self.start];
+        if first == '0' && matches!(self.peek(), 'x' | 'b' | 'o') {
             self.advance();
+            self.digits();
+            return self.make_token(TokenType::Integer);
         }
 
+        self.digits();
         if self.peek() == '.' && self.is_digit(self.peek_next()) {
             // we're in the middle of a float, looking at decimal now
             // consume decimal, and capture rest of num
             self.advance();
-            while self.is_digit(self.peek()) {
-                self.advance();
-            }
+            self.digits();
+        }
+
+        // the sign of an exponent, as in `1e-3`
+        let last = self.source[self.current - 1];
+        if matches!(last, 'e' | 'E') && matches!(self.peek(), '+' | '-') && self.is_digit(self.peek_next()) {
+            self.advance();
+            self.digits();
         }
 
-        self.make_token(TokenType::Number)
+        let text = &self.source[self.start..self.current];
+        if text.iter().any(|ch| matches!(ch, '.' | 'e' | 'E')) {
+            self.make_token(TokenType::Number)
+        } else {
+            self.make_token(TokenType::Integer)
+        }
+    }
+
+    fn digits(&mut self) -> () {
+        while unicode::is_xid_continue(self.peek()) {
+            self.advance();
+        }
     }
 
+    // identifiers follow Unicode's rules, so `café` and `名前` are names just like `name`
     fn is_alpha(&self, ch: char) -> bool {
-        (ch >= 'a' && ch <= 'z') ||
-        (ch >= 'A' && ch <= 'A') ||
-        ch == '_'
+        ch == '_' || unicode::is_xid_start(ch)
     }
     
     fn identifier(&mut self) -> Token {
-        while self.is_alpha(self.peek()) || self.is_digit(self.peek()) {
+        while unicode::is_xid_continue(self.peek()) {
             self.advance();
         }
 
@@ -155,7 +316,20 @@ impl Scanner {
     fn identifier_type(&self) -> TokenType {
         match self.source[self.start] {
             'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            'b' => self.check_keyword(1, 4, "reak", TokenType::Break),
+            'c' => {
+                if self.current - self.start > 1 {
+                    match self.source[self.start + 1] {
+                        'a' => self.check_keyword(2, 2, "se", TokenType::Case),
+                        'l' => self.check_keyword(2, 3, "ass", TokenType::Class),
+                        'o' => self.check_keyword(2, 6, "ntinue", TokenType::Continue),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            },
+            'd' => self.check_keyword(1, 6, "efault", TokenType::Default),
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
             'f' => {
                 if self.current - self.start > 1 {
@@ -174,7 +348,17 @@ impl Scanner {
             'o' => self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
             'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, 4, "uper", TokenType::Super),
+            's' => {
+                if self.current - self.start > 1 {
+                    match self.source[self.start + 1] {
+                        'u' => self.check_keyword(2, 3, "per", TokenType::Super),
+                        'w' => self.check_keyword(2, 4, "itch", TokenType::Switch),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            },
             't' => {
                 if self.current - self.start > 1 {
                     match self.source[self.start + 1] {
@@ -192,17 +376,27 @@ impl Scanner {
         }
     }
 
+    // every character goes through here, so this is the one place lines are counted
     fn advance(&mut self) -> char {
+        let ch = self.source[self.current];
         self.current += 1;
-        self.source[self.current - 1]
+        if ch == '\n' {
+            self.line += 1;
+            self.line_start = self.current;
+        }
+        ch
     }
 
     fn check_keyword(&self, start: usize, length: usize, rest: &str, token_type: TokenType) -> TokenType {
+        // compare lengths first, a short identifier at the end of the source can't be sliced that far
+        if self.current - self.start != start + length {
+            return TokenType::Identifier;
+        }
+
         let start_idx : usize = self.start + start;
         let end_idx : usize = start_idx + length;
-        let substr : String = self.source[start_idx..end_idx].into_iter().collect();
-        if (self.current - self.start == start + length) 
-            && substr == rest {
+        let substr : String = self.source[start_idx..end_idx].iter().collect();
+        if substr == rest {
             token_type    
         } else {
             TokenType::Identifier
@@ -210,10 +404,8 @@ impl Scanner {
     }
 
     fn match_token(&mut self, expected: char) -> bool {
-        if self.is_at_end() { 
+        if self.is_at_end() || self.source[self.current] != expected { 
             false 
-        } else if self.source[self.current] != expected { 
-           false 
         } else {
             self.current += 1;
             true
@@ -226,11 +418,7 @@ impl Scanner {
         loop {
             let c: char = self.peek();
             match c {
-                ' ' | '\r' | '\t' => {
-                    self.advance();
-                },
-                '\n' => {
-                    self.line += 1;
+                ' ' | '\r' | '\t' | '\n' => {
                     self.advance();
                 },
                 '/' => {
@@ -238,6 +426,9 @@ impl Scanner {
                         while self.peek() != '\n' && !self.is_at_end() {
                             self.advance();
                         }    
+                    } else {
+                        // a lone slash is division, leave it for scan_token
+                        break ();
                     }
                 }
                 _ => break (),
@@ -254,7 +445,7 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
+        if self.current + 1 >= self.len {
             '\0'
         } else {
             self.source[self.current + 1]
@@ -262,35 +453,141 @@ impl Scanner {
     }
 }
 
+// the text of a string token with its escape sequences replaced,
+// the scanner has already rejected any that aren't valid
+pub fn unescape(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('u') => {
+                let digits: String = chars.by_ref().skip(1).take_while(|ch| *ch != '}').collect();
+                let code = u32::from_str_radix(&digits, 16).unwrap();
+                out.push(char::from_u32(code).unwrap());
+            },
+            Some(other) => out.push(other),
+            None => (),
+        }
+    }
+    out
+}
+
+// the value of an integer token, or why it isn't a valid literal
+pub fn parse_integer(text: &str) -> Result<i64, String> {
+    let (radix, body) = match text.get(..2) {
+        Some("0x") => (16, &text[2..]),
+        Some("0b") => (2, &text[2..]),
+        Some("0o") => (8, &text[2..]),
+        _ => (10, text),
+    };
+
+    let digits = without_separators(text, body, radix)?;
+    if digits.is_empty() || !digits.chars().all(|ch| ch.is_digit(radix)) {
+        return Err(format!("Invalid number literal '{}'.", text));
+    }
+    i64::from_str_radix(&digits, radix)
+        .map_err(|_| format!("Integer literal '{}' is too large.", text))
+}
+
+// the value of a number token, or why it isn't a valid literal
+pub fn parse_number(text: &str) -> Result<f64, String> {
+    let digits = without_separators(text, text, 10)?;
+    if !is_decimal(&digits) {
+        return Err(format!("Invalid number literal '{}'.", text));
+    }
+    match digits.parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(format!("Number literal '{}' is too large.", text)),
+    }
+}
+
+// the digits of a literal with its `_` separators dropped, which may only sit between two digits
+fn without_separators(text: &str, body: &str, radix: u32) -> Result<String, String> {
+    let chars: Vec<char> = body.chars().collect();
+    for (i, ch) in chars.iter().enumerate() {
+        if *ch == '_' {
+            let between_digits = i > 0 && chars[i - 1].is_digit(radix)
+                && chars.get(i + 1).is_some_and(|next| next.is_digit(radix));
+            if !between_digits {
+                return Err(format!("Invalid number literal '{}', '_' must sit between two digits.", text));
+            }
+        }
+    }
+    Ok(chars.into_iter().filter(|ch| *ch != '_').collect())
+}
+
+// digits, then optionally `.` and digits, then optionally `e` or `E`, a sign and digits
+// rust's own float parsing is more lenient than that, it takes `1.`, `.5` and `inf`
+fn is_decimal(text: &str) -> bool {
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(at) => (&text[..at], Some(&text[at + 1..])),
+        None => (text, None),
+    };
+    let (whole, fraction) = match mantissa.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (mantissa, None),
+    };
+    let exponent = exponent.map(|exponent| exponent.strip_prefix(['+', '-']).unwrap_or(exponent));
+
+    let all_digits = |part: &str| !part.is_empty() && part.chars().all(|ch| ch.is_ascii_digit());
+    all_digits(whole) && fraction.is_none_or(all_digits) && exponent.is_none_or(all_digits)
+}
+
 #[derive(Clone)]
 pub struct Token {
     pub token_type: TokenType,
+    // where the token starts, both 1 based
     pub line: i32,
+    pub column: usize,
     pub start: usize,
+    // how many source characters the token covers, error tokens included
+    pub length: usize,
     pub lexeme: String
 }
 
+impl Token {
+    pub fn span(&self) -> Span {
+        Span { start: self.start, end: self.start + self.length }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum TokenType {
     // single characters
     LeftParen, RightParen,
     LeftBrace, RightBrace,
+    LeftBracket, RightBracket,
     Comma, Dot, Minus, Plus,
-    Semicolon, Slash, Star,
+    Semicolon, Slash, Star, Colon,
+    Percent, Ampersand, Pipe, Caret, Tilde,
 
     // one or two character tokens
     Bang, BangEqual,
     Equal, EqualEqual,
-    Greater, GreaterEqual,
-    Less, LessEqual,
+    Greater, GreaterEqual, GreaterGreater,
+    Less, LessEqual, LessLess,
+    PlusEqual, PlusPlus,
+    MinusEqual, MinusMinus,
+    StarEqual, StarStar, SlashEqual,
     
     // Literals
-    Identifier, TokenString, Number,
+    Identifier, TokenString, Integer, Number,
+    // the part of a string before a `${`, or between a `}` and the next `${`
+    Interpolation,
 
     // keywords
-    And, Class, Else, False,
+    And, Break, Case, Class, Continue, Default, Else, False,
     For, Fun, If, Nil, Or,
-    Print, Return, Super, This,
+    Print, Return, Super, Switch, This,
     True, Var, While,
 
     Error, 
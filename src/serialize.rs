@@ -0,0 +1,480 @@
+use std::mem;
+
+use crate::chunk::{Chunk, Location, LocationRun, OpCode};
+use crate::diagnostic::Span;
+use crate::memory::Heap;
+use crate::object::{Function, Obj, ObjRef};
+use crate::value::{Value, ValueType};
+
+// precompiled scripts, so a program doesn't have to be parsed again on every run
+//
+// a .loxc file is laid out as
+//   magic     4 bytes  "LOXC"
+//   version   u16      FORMAT_VERSION of the compiler that wrote it
+//   checksum  u32      FNV-1a over everything after the header
+//   body               the script function, encoded by write_function
+// all integers are little endian
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+// bump this whenever the encoding or the instruction set changes,
+// old files are rejected instead of being misread
+pub const FORMAT_VERSION: u16 = 9;
+
+const HEADER_SIZE: usize = 4 + 2 + 4;
+
+// tags for the constants in a function's pool
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+const TAG_INT: u8 = 6;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn serialize(function: ObjRef, heap: &Heap) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_function(&mut body, function, heap);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&body).to_le_bytes());
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+// loads a script function back onto the heap
+// every nested function prototype and string constant is allocated along the way
+pub fn deserialize(bytes: &[u8], heap: &mut Heap) -> Result<ObjRef, String> {
+    if !is_bytecode(bytes) || bytes.len() < HEADER_SIZE {
+        return Err("Not a compiled lox file.".to_string());
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "Compiled with bytecode version {}, but this interpreter runs version {}. Recompile the script.",
+            version, FORMAT_VERSION
+        ));
+    }
+
+    let expected = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let body = &bytes[HEADER_SIZE..];
+    if checksum(body) != expected {
+        return Err("Compiled file is corrupt (checksum mismatch).".to_string());
+    }
+
+    let mut reader = Reader { bytes: body, position: 0 };
+    let function = reader.function(heap)?;
+    if reader.position != body.len() {
+        return Err("Compiled file has trailing data.".to_string());
+    }
+
+    // the script is called with no arguments and has nothing to capture
+    let script = heap.function(function);
+    if script.arity != 0 || script.upvalue_count != 0 {
+        return Err("Compiled file is malformed, its script takes arguments.".to_string());
+    }
+    Ok(function)
+}
+
+// 32 bit FNV-1a, plenty to catch truncated or damaged files
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+/*
+ * writing
+ */
+
+fn write_u32(out: &mut Vec<u8>, value: usize) -> () {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) -> () {
+    write_u32(out, string.len());
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, function: ObjRef, heap: &Heap) -> () {
+    let function = heap.function(function);
+    match function.name {
+        Some(name) => {
+            out.push(1);
+            write_string(out, heap.string(name));
+        },
+        None => out.push(0),
+    }
+    write_string(out, heap.string(function.file));
+    write_u32(out, function.arity);
+    write_u32(out, function.upvalue_count);
+
+    let chunk = &function.chunk;
+    write_u32(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+
+    write_u32(out, chunk.locations.len());
+    for run in chunk.locations.iter() {
+        let location = run.location;
+        out.extend_from_slice(&location.line.to_le_bytes());
+        write_u32(out, location.column);
+        write_u32(out, location.span.start);
+        write_u32(out, location.span.end);
+        write_u32(out, run.count);
+    }
+
+    write_u32(out, chunk.constants.len());
+    for constant in chunk.constants.iter() {
+        match constant.value_type {
+            ValueType::Nil => out.push(TAG_NIL),
+            ValueType::Bool(false) => out.push(TAG_FALSE),
+            ValueType::Bool(true) => out.push(TAG_TRUE),
+            ValueType::Number(number) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&number.to_bits().to_le_bytes());
+            },
+            ValueType::Int(int) => {
+                out.push(TAG_INT);
+                out.extend_from_slice(&int.to_le_bytes());
+            },
+            ValueType::String(string) => {
+                out.push(TAG_STRING);
+                write_string(out, heap.string(string));
+            },
+            ValueType::Function(nested) => {
+                out.push(TAG_FUNCTION);
+                write_function(out, nested, heap);
+            },
+            // the compiler only ever puts the kinds above in a constant pool
+            _ => panic!("serialize found a runtime-only value in a constant pool"),
+        }
+    }
+}
+
+/*
+ * reading
+ */
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], String> {
+        if self.bytes.len() - self.position < count {
+            return Err("Compiled file ends unexpectedly.".to_string());
+        }
+
+        let slice = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        match std::str::from_utf8(bytes) {
+            Ok(string) => Ok(string.to_string()),
+            Err(_) => Err("Compiled file contains a malformed string.".to_string()),
+        }
+    }
+
+    fn function(&mut self, heap: &mut Heap) -> Result<ObjRef, String> {
+        let name = match self.byte()? {
+            0 => None,
+            _ => {
+                let name = self.string()?;
+                Some(heap.intern(&name))
+            }
+        };
+
+        let file = self.string()?;
+        let mut function = Function::new(name, heap.intern(&file));
+        function.arity = self.u32()?;
+        function.upvalue_count = self.u32()?;
+        function.chunk = self.chunk(heap)?;
+        verify(&function, heap)?;
+        Ok(heap.alloc(Obj::Function(function)))
+    }
+
+    fn chunk(&mut self, heap: &mut Heap) -> Result<Chunk, String> {
+        let mut chunk = Chunk::new_chunk();
+
+        let code_len = self.u32()?;
+        chunk.code = self.take(code_len)?.to_vec();
+
+        let location_count = self.u32()?;
+        for _ in 0..location_count {
+            let line = self.take(4)?;
+            let line = i32::from_le_bytes([line[0], line[1], line[2], line[3]]);
+            let column = self.u32()?;
+            let span = Span { start: self.u32()?, end: self.u32()? };
+            let count = self.u32()?;
+            chunk.locations.push(LocationRun { location: Location { line, column, span }, count });
+        }
+
+        let constant_count = self.u32()?;
+        for _ in 0..constant_count {
+            let constant = match self.byte()? {
+                TAG_NIL => Value::nil_val(),
+                TAG_FALSE => Value::bool_val(false),
+                TAG_TRUE => Value::bool_val(true),
+                TAG_NUMBER => {
+                    let bytes = self.take(mem::size_of::<u64>())?;
+                    let mut bits = [0u8; 8];
+                    bits.copy_from_slice(bytes);
+                    Value::number_val(f64::from_bits(u64::from_le_bytes(bits)))
+                },
+                TAG_INT => {
+                    let bytes = self.take(mem::size_of::<i64>())?;
+                    let mut int = [0u8; 8];
+                    int.copy_from_slice(bytes);
+                    Value::int_val(i64::from_le_bytes(int))
+                },
+                TAG_STRING => {
+                    let string = self.string()?;
+                    Value::string_val(heap.intern(&string))
+                },
+                TAG_FUNCTION => Value::function_val(self.function(heap)?),
+                tag => return Err(format!("Compiled file has an unknown constant tag {}.", tag)),
+            };
+            // pushed directly, add_constant would merge entries and shift the indices
+            chunk.constants.push(constant);
+        }
+
+        Ok(chunk)
+    }
+}
+
+/*
+ * verifying
+ */
+
+// the checksum only proves the file arrived as written, not that a compiler wrote it
+// this rejects anything that would make the VM index out of bounds while decoding instructions:
+// unknown opcodes, operands running off the end, constants that are missing or of the wrong kind,
+// jumps landing outside the code or inside an instruction, and source locations not matching the code
+// nested functions are verified as they're read, so their upvalue counts are already known here
+// check_stack then follows every path through the code to rule out stack underflow and bad local slots
+fn verify(function: &Function, heap: &Heap) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let malformed = |what: String| Err(format!("Compiled file is malformed, {}.", what));
+
+    let covered: usize = chunk.locations.iter().map(|run| run.count).sum();
+    if covered != chunk.code.len() {
+        return malformed("its source locations don't match its code".to_string());
+    }
+
+    // where the instruction starting at each offset ends, None inside instructions
+    let mut ends = vec![None; chunk.code.len()];
+    let mut jumps = Vec::new();
+    let mut offset = 0;
+    let mut last = None;
+    while offset < chunk.code.len() {
+        let op = match OpCode::from_byte(chunk.code[offset]) {
+            Some(op) => op,
+            None => return malformed(format!("unknown opcode {} at offset {}", chunk.code[offset], offset)),
+        };
+        last = Some(op);
+
+        // the operand is a constant index, maybe followed by an argument count,
+        // a single byte, or a two byte jump distance
+        // most instructions with a constant operand use it as a name, which has to be a string
+        let index_width = if op.is_long() { 3 } else { 1 };
+        let (names_constant, operand_width) = match op {
+            OpCode::Constant | OpCode::ConstantLong
+                | OpCode::Closure | OpCode::ClosureLong => (false, index_width),
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong
+                | OpCode::GetGlobal | OpCode::GetGlobalLong
+                | OpCode::SetGlobal | OpCode::SetGlobalLong
+                | OpCode::Class | OpCode::ClassLong
+                | OpCode::Method | OpCode::MethodLong
+                | OpCode::GetProperty | OpCode::GetPropertyLong
+                | OpCode::SetProperty | OpCode::SetPropertyLong
+                | OpCode::GetSuper | OpCode::GetSuperLong => (true, index_width),
+            OpCode::Invoke | OpCode::InvokeLong
+                | OpCode::SuperInvoke | OpCode::SuperInvokeLong => (true, index_width + 1),
+            OpCode::SetLocal | OpCode::GetLocal
+                | OpCode::Call
+                | OpCode::GetUpvalue | OpCode::SetUpvalue
+                | OpCode::BuildList | OpCode::BuildMap
+                | OpCode::Dup | OpCode::Bury => (false, 1),
+            OpCode::JumpIfFalse | OpCode::Jump | OpCode::Loop => (false, 2),
+            _ => (false, 0),
+        };
+        let next = offset + 1 + operand_width;
+        if next > chunk.code.len() {
+            return malformed(format!("the instruction at offset {} runs past the end of the code", offset));
+        }
+        if names_constant && !Value::is_string(&constant_at(chunk, offset, op)?) {
+            return malformed(format!("the instruction at offset {} names a constant that isn't a string", offset));
+        }
+
+        match op {
+            OpCode::Constant | OpCode::ConstantLong => {
+                constant_at(chunk, offset, op)?;
+            },
+            OpCode::Closure | OpCode::ClosureLong => {
+                let nested = constant_at(chunk, offset, op)?;
+                if !Value::is_function(&nested) {
+                    return malformed(format!("the closure at offset {} refers to a constant that isn't a function", offset));
+                }
+                // an (is_local, index) pair per captured variable
+                let captures = heap.function(Value::as_function(nested)).upvalue_count;
+                if next + 2 * captures > chunk.code.len() {
+                    return malformed(format!("the instruction at offset {} runs past the end of the code", offset));
+                }
+                for capture in chunk.code[next..next + 2 * captures].chunks(2) {
+                    let enclosing_upvalue = capture[0] == 0;
+                    if capture[0] > 1 || enclosing_upvalue && capture[1] as usize >= function.upvalue_count {
+                        return malformed(format!("the closure at offset {} captures a variable that doesn't exist", offset));
+                    }
+                }
+                ends[offset] = Some(next + 2 * captures);
+                offset = next + 2 * captures;
+                continue;
+            },
+            OpCode::GetUpvalue | OpCode::SetUpvalue if chunk.code[offset + 1] as usize >= function.upvalue_count => {
+                return malformed(format!("the instruction at offset {} uses an upvalue that doesn't exist", offset));
+            },
+            OpCode::JumpIfFalse | OpCode::Jump => jumps.push((offset, next + chunk.read_u16(offset + 1) as usize)),
+            OpCode::Loop => match next.checked_sub(chunk.read_u16(offset + 1) as usize) {
+                Some(target) => jumps.push((offset, target)),
+                None => return malformed(format!("the loop at offset {} jumps before the start of the code", offset)),
+            },
+            _ => (),
+        }
+        ends[offset] = Some(next);
+        offset = next;
+    }
+
+    // falling off the end of the code would read past it
+    if last != Some(OpCode::Return) {
+        return malformed("its code doesn't end with a return".to_string());
+    }
+    let mut targets = vec![None; chunk.code.len()];
+    for (offset, target) in jumps {
+        if target >= chunk.code.len() || ends[target].is_none() {
+            return malformed(format!("the jump at offset {} doesn't land on an instruction", offset));
+        }
+        targets[offset] = Some(target);
+    }
+    check_stack(function, &ends, &targets)
+}
+
+// walks every path through already decoded code, tracking how many values the frame holds
+// so no instruction pops more than is there or reaches a local slot past the top,
+// and every path into an instruction agrees on the depth there
+fn check_stack(function: &Function, ends: &[Option<usize>], targets: &[Option<usize>]) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let malformed = |what: String| Err(format!("Compiled file is malformed, {}.", what));
+
+    // the callee and its arguments are on the stack when the frame starts
+    let mut depths = vec![None; chunk.code.len()];
+    let mut pending = vec![(0, 1 + function.arity)];
+    while let Some((offset, depth)) = pending.pop() {
+        match depths[offset] {
+            Some(seen) if seen == depth => continue,
+            Some(_) => return malformed(format!("the paths reaching offset {} leave different amounts on the stack", offset)),
+            None => depths[offset] = Some(depth),
+        }
+
+        let op = OpCode::from_byte(chunk.code[offset]).unwrap();
+        let byte = |at: usize| chunk.code[offset + at] as usize;
+        let index_width = if op.is_long() { 3 } else { 1 };
+        // how deep the stack has to be, then how many values the instruction pops and pushes
+        let (needed, popped, pushed) = match op {
+            OpCode::Constant | OpCode::ConstantLong
+                | OpCode::GetGlobal | OpCode::GetGlobalLong
+                | OpCode::Class | OpCode::ClassLong
+                | OpCode::GetUpvalue
+                | OpCode::True | OpCode::False | OpCode::Nil => (0, 0, 1),
+            OpCode::GetLocal => (byte(1) + 1, 0, 1),
+            OpCode::SetLocal => (byte(1) + 1, 0, 0),
+            OpCode::Closure | OpCode::ClosureLong => {
+                // an (is_local, index) pair per captured variable, locals are captured off this frame
+                let captures = &chunk.code[offset + 1 + index_width..ends[offset].unwrap()];
+                let needed = captures.chunks(2)
+                    .filter(|capture| capture[0] == 1)
+                    .map(|capture| capture[1] as usize + 1)
+                    .max()
+                    .unwrap_or(0);
+                (needed, 0, 1)
+            },
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong
+                | OpCode::CloseUpvalue | OpCode::Pop | OpCode::Print => (1, 1, 0),
+            OpCode::SetGlobal | OpCode::SetGlobalLong
+                | OpCode::SetUpvalue
+                | OpCode::GetProperty | OpCode::GetPropertyLong
+                | OpCode::JumpIfFalse
+                | OpCode::Not | OpCode::Negate | OpCode::BitNot
+                | OpCode::Stringify
+                | OpCode::Return => (1, 0, 0),
+            OpCode::Method | OpCode::MethodLong
+                | OpCode::SetProperty | OpCode::SetPropertyLong
+                | OpCode::Inherit
+                | OpCode::GetSuper | OpCode::GetSuperLong
+                | OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide
+                | OpCode::Modulo | OpCode::Power
+                | OpCode::Equal | OpCode::Greater | OpCode::Less
+                | OpCode::BitAnd | OpCode::BitOr | OpCode::BitXor
+                | OpCode::ShiftLeft | OpCode::ShiftRight
+                | OpCode::GetIndex => (2, 1, 0),
+            OpCode::SetIndex => (3, 2, 0),
+            // the result replaces the callee, or the receiver, and the arguments
+            OpCode::Call => (byte(1) + 1, byte(1), 0),
+            OpCode::Invoke | OpCode::InvokeLong => (byte(1 + index_width) + 1, byte(1 + index_width), 0),
+            // the superclass sits on top of the arguments
+            OpCode::SuperInvoke | OpCode::SuperInvokeLong => (byte(1 + index_width) + 2, byte(1 + index_width) + 1, 0),
+            OpCode::BuildList => (byte(1), byte(1), 1),
+            OpCode::BuildMap => (2 * byte(1), 2 * byte(1), 1),
+            OpCode::Dup => (byte(1) + 1, 0, 1),
+            OpCode::Bury => (byte(1) + 1, 0, 0),
+            OpCode::Jump | OpCode::Loop => (0, 0, 0),
+        };
+        if depth < needed {
+            return malformed(format!("the instruction at offset {} uses more of the stack than it holds", offset));
+        }
+        let depth = depth - popped + pushed;
+
+        match op {
+            OpCode::Return => (),
+            OpCode::Jump | OpCode::Loop => pending.push((targets[offset].unwrap(), depth)),
+            OpCode::JumpIfFalse => {
+                pending.push((targets[offset].unwrap(), depth));
+                pending.push((ends[offset].unwrap(), depth));
+            },
+            // the code ends with a return, so anything else is followed by another instruction
+            _ => pending.push((ends[offset].unwrap(), depth)),
+        }
+    }
+    Ok(())
+}
+
+// the constant an instruction's index operand refers to
+fn constant_at(chunk: &Chunk, offset: usize, op: OpCode) -> Result<Value, String> {
+    let index = if op.is_long() { chunk.read_u24(offset + 1) } else { chunk.code[offset + 1] as usize };
+    match chunk.constants.get(index) {
+        Some(constant) => Ok(*constant),
+        None => Err(format!("Compiled file is malformed, the instruction at offset {} uses constant {} of {}.", offset, index, chunk.constants.len())),
+    }
+}
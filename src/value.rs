@@ -1,16 +1,31 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
 use std::fmt;
 
-#[derive(Clone)]
+use crate::object::ObjRef;
+
+// objects are stored on the VM heap, values only hold a handle to them
+// which keeps values small and cheap to copy
+#[derive(Clone, Copy)]
 pub enum ValueType {
 	Bool(bool),
 	Nil,
 	Number(f64),
-        String(String),
+	Int(i64),
+        String(ObjRef),
+        Function(ObjRef),
+        Closure(ObjRef),
+        Class(ObjRef),
+        Instance(ObjRef),
+        BoundMethod(ObjRef),
+        Native(ObjRef),
+        List(ObjRef),
+        Map(ObjRef),
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct Value {
-	value_type: ValueType,
+	pub(crate) value_type: ValueType,
 }
 
 impl Value {
@@ -26,12 +41,66 @@ impl Value {
 		}
 	}
 
-        pub fn string_val(val: String) -> Value {
+	pub fn int_val(val: i64) -> Value {
+		Value {
+			value_type: ValueType::Int(val)
+		}
+	}
+
+        pub(crate) fn string_val(val: ObjRef) -> Value {
             Value {
                 value_type: ValueType::String(val)
             }
         }
 
+        pub(crate) fn function_val(val: ObjRef) -> Value {
+            Value {
+                value_type: ValueType::Function(val)
+            }
+        }
+
+        pub(crate) fn closure_val(val: ObjRef) -> Value {
+            Value {
+                value_type: ValueType::Closure(val)
+            }
+        }
+
+        pub(crate) fn class_val(val: ObjRef) -> Value {
+            Value {
+                value_type: ValueType::Class(val)
+            }
+        }
+
+        pub(crate) fn instance_val(val: ObjRef) -> Value {
+            Value {
+                value_type: ValueType::Instance(val)
+            }
+        }
+
+        pub(crate) fn bound_method_val(val: ObjRef) -> Value {
+            Value {
+                value_type: ValueType::BoundMethod(val)
+            }
+        }
+
+        pub(crate) fn native_val(val: ObjRef) -> Value {
+            Value {
+                value_type: ValueType::Native(val)
+            }
+        }
+
+        pub(crate) fn list_val(val: ObjRef) -> Value {
+            Value {
+                value_type: ValueType::List(val)
+            }
+        }
+
+        pub(crate) fn map_val(val: ObjRef) -> Value {
+            Value {
+                value_type: ValueType::Map(val)
+            }
+        }
+
         pub fn nil_val() -> Value {
             Value {
                 value_type: ValueType::Nil
@@ -52,40 +121,197 @@ impl Value {
 		}
 	}
 
-        pub fn as_string(val: Value) -> String {
+	pub fn as_int(val: Value) -> i64 {
+		match val.value_type {
+			ValueType::Int(val) => val,
+			_ => panic!("Value::as_int should never be called on a non-i64 type")
+		}
+	}
+
+        // either kind of number as a float, ints are promoted, None for anything else
+        pub fn to_float(val: &Value) -> Option<f64> {
+            match val.value_type {
+                ValueType::Number(number) => Some(number),
+                ValueType::Int(int) => Some(int as f64),
+                _ => None,
+            }
+        }
+
+        // the float as an int, if it's a whole number an i64 can hold exactly
+        pub fn exact_int(number: f64) -> Option<i64> {
+            // 2^63 is exactly representable, every float below it in magnitude that's whole fits
+            if number.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&number) {
+                Some(number as i64)
+            } else {
+                None
+            }
+        }
+
+        pub(crate) fn as_string(val: Value) -> ObjRef {
             match val.value_type {
                 ValueType::String(string) => string,
                 _ => panic!("Value::as_string should never be called on non-string"),
             }
         }
 
-        pub fn is_number(val: &Value) -> bool {
+        pub(crate) fn as_function(val: Value) -> ObjRef {
             match val.value_type {
-                ValueType::Number(_) => true,
-                _ => false,
+                ValueType::Function(function) => function,
+                _ => panic!("Value::as_function should never be called on non-function"),
             }
         }
 
-        pub fn is_bool(val: &Value) -> bool {
+        pub(crate) fn as_closure(val: Value) -> ObjRef {
             match val.value_type {
-                ValueType::Bool(_) => true,
-                _ => false,
+                ValueType::Closure(closure) => closure,
+                _ => panic!("Value::as_closure should never be called on non-closure"),
             }
         }
 
-        pub fn is_string(val: &Value) -> bool {
+        pub(crate) fn as_class(val: Value) -> ObjRef {
             match val.value_type {
-                ValueType::String(_) => true,
-                _ => false,
+                ValueType::Class(class) => class,
+                _ => panic!("Value::as_class should never be called on non-class"),
             }
         }
 
+        pub(crate) fn as_instance(val: Value) -> ObjRef {
+            match val.value_type {
+                ValueType::Instance(instance) => instance,
+                _ => panic!("Value::as_instance should never be called on non-instance"),
+            }
+        }
+
+        pub(crate) fn as_bound_method(val: Value) -> ObjRef {
+            match val.value_type {
+                ValueType::BoundMethod(bound) => bound,
+                _ => panic!("Value::as_bound_method should never be called on non-bound-method"),
+            }
+        }
+
+        pub(crate) fn as_native(val: Value) -> ObjRef {
+            match val.value_type {
+                ValueType::Native(native) => native,
+                _ => panic!("Value::as_native should never be called on non-native"),
+            }
+        }
+
+        pub(crate) fn as_list(val: Value) -> ObjRef {
+            match val.value_type {
+                ValueType::List(list) => list,
+                _ => panic!("Value::as_list should never be called on non-list"),
+            }
+        }
+
+        pub(crate) fn as_map(val: Value) -> ObjRef {
+            match val.value_type {
+                ValueType::Map(map) => map,
+                _ => panic!("Value::as_map should never be called on non-map"),
+            }
+        }
+
+        // the heap handle behind any object value, None for the immediate types
+        pub(crate) fn as_obj_ref(val: &Value) -> Option<ObjRef> {
+            match val.value_type {
+                ValueType::Bool(_) | ValueType::Nil | ValueType::Number(_) | ValueType::Int(_) => None,
+                ValueType::String(handle)
+                    | ValueType::Function(handle)
+                    | ValueType::Closure(handle)
+                    | ValueType::Class(handle)
+                    | ValueType::Instance(handle)
+                    | ValueType::BoundMethod(handle)
+                    | ValueType::Native(handle)
+                    | ValueType::List(handle)
+                    | ValueType::Map(handle) => Some(handle),
+            }
+        }
+
+        pub fn is_number(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::Number(_))
+        }
+
+        pub fn is_int(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::Int(_))
+        }
+
+        // an int or a float
+        pub fn is_numeric(val: &Value) -> bool {
+            Value::to_float(val).is_some()
+        }
+
+        pub fn is_bool(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::Bool(_))
+        }
+
+        pub fn is_string(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::String(_))
+        }
+
+        pub fn is_function(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::Function(_))
+        }
+
+        pub fn is_closure(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::Closure(_))
+        }
+
+        pub fn is_class(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::Class(_))
+        }
+
+        pub fn is_instance(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::Instance(_))
+        }
+
+        pub fn is_bound_method(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::BoundMethod(_))
+        }
+
+        pub fn is_native(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::Native(_))
+        }
+
+        pub fn is_list(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::List(_))
+        }
+
+        pub fn is_map(val: &Value) -> bool {
+            matches!(val.value_type, ValueType::Map(_))
+        }
+
+        // checks `val` is a whole number usable as an index into a list of `len` items
+        pub fn as_index(val: &Value, len: usize) -> Result<usize, String> {
+            // whole floats are still accepted, they're what `len` returned before there were ints
+            let index = match val.value_type {
+                ValueType::Int(index) => index,
+                ValueType::Number(index) => match Value::exact_int(index) {
+                    Some(index) => index,
+                    None => return Err("List index must be an integer.".to_string()),
+                },
+                _ => return Err("List index must be an integer.".to_string()),
+            };
+
+            if index < 0 || index as u64 >= len as u64 {
+                return Err("List index out of range.".to_string());
+            }
+            Ok(index as usize)
+        }
+
         pub fn is_falsey(val: &Value) -> bool {
             match val.value_type {
                 ValueType::Nil => true,
                 ValueType::Bool(bool_val) => !bool_val,
                 ValueType::Number(_) => false,
+                ValueType::Int(_) => false,
                 ValueType::String(_) => false,
+                ValueType::Function(_) => false,
+                ValueType::Closure(_) => false,
+                ValueType::Class(_) => false,
+                ValueType::Instance(_) => false,
+                ValueType::BoundMethod(_) => false,
+                ValueType::Native(_) => false,
+                ValueType::List(_) => false,
+                ValueType::Map(_) => false,
             }
         }
 
@@ -94,21 +320,132 @@ impl Value {
                 (ValueType::Bool(a_val), ValueType::Bool(b_val)) => a_val == b_val,
                 (ValueType::Nil, ValueType::Nil) => true,
                 (ValueType::Number(a_val), ValueType::Number(b_val)) => a_val == b_val,
-                (ValueType::String(s), ValueType::String(a)) => a == s,
+                (ValueType::Int(a_val), ValueType::Int(b_val)) => a_val == b_val,
+                // an int and a float are equal when they're the same number,
+                // compared exactly rather than by rounding the int to a float
+                (ValueType::Int(int), ValueType::Number(number))
+                    | (ValueType::Number(number), ValueType::Int(int)) => Value::exact_int(number) == Some(int),
+                // objects are equal only to themselves
+                // strings are interned, so equal contents always means the same handle
+                (ValueType::String(a_obj), ValueType::String(b_obj))
+                    | (ValueType::Function(a_obj), ValueType::Function(b_obj))
+                    | (ValueType::Closure(a_obj), ValueType::Closure(b_obj))
+                    | (ValueType::Class(a_obj), ValueType::Class(b_obj))
+                    | (ValueType::Instance(a_obj), ValueType::Instance(b_obj))
+                    | (ValueType::BoundMethod(a_obj), ValueType::BoundMethod(b_obj))
+                    | (ValueType::Native(a_obj), ValueType::Native(b_obj))
+                    | (ValueType::List(a_obj), ValueType::List(b_obj))
+                    | (ValueType::Map(a_obj), ValueType::Map(b_obj)) => a_obj == b_obj,
                 _ => false,
             }
         }
 
+        // orders two numbers, None when either is NaN or isn't a number
+        // an int and a float are compared exactly, agreeing with values_equal,
+        // where rounding the int to a float could make distinct numbers tie
+        pub fn compare_numbers(a: Value, b: Value) -> Option<Ordering> {
+            match (a.value_type, b.value_type) {
+                (ValueType::Int(a), ValueType::Int(b)) => Some(a.cmp(&b)),
+                (ValueType::Number(a), ValueType::Number(b)) => a.partial_cmp(&b),
+                (ValueType::Int(int), ValueType::Number(number)) => Value::compare_int_float(int, number),
+                (ValueType::Number(number), ValueType::Int(int)) => Value::compare_int_float(int, number).map(Ordering::reverse),
+                _ => None,
+            }
+        }
+
+        fn compare_int_float(int: i64, number: f64) -> Option<Ordering> {
+            // every int lies in [-2^63, 2^63), and both ends are exact floats
+            const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+            if number.is_nan() {
+                None
+            } else if number >= LIMIT {
+                Some(Ordering::Less)
+            } else if number < -LIMIT {
+                Some(Ordering::Greater)
+            } else {
+                // the whole part fits in an int exactly, the fraction only breaks a tie
+                match int.cmp(&(number.trunc() as i64)) {
+                    Ordering::Equal => 0.0_f64.partial_cmp(&number.fract()),
+                    ordering => Some(ordering),
+                }
+            }
+        }
+
+}
+
+impl From<bool> for Value {
+    fn from(val: bool) -> Value {
+        Value::bool_val(val)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Value {
+        Value::number_val(val)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(val: i64) -> Value {
+        Value::int_val(val)
+    }
+}
+
+// getting rust values back out, for hosts reading native arguments and results
+// the error is a message, so natives can pass it straight on with `?`
+// strings live on the heap, read them with VM::as_str
+impl TryFrom<Value> for bool {
+    type Error = String;
+
+    fn try_from(val: Value) -> Result<bool, String> {
+        match val.value_type {
+            ValueType::Bool(b) => Ok(b),
+            _ => Err("Expected a bool.".to_string()),
+        }
+    }
+}
+
+// ints are converted, rounding beyond 2^53 like float() does
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    fn try_from(val: Value) -> Result<f64, String> {
+        Value::to_float(&val).ok_or_else(|| "Expected a number.".to_string())
+    }
+}
+
+// whole floats are accepted, the same as list indexes
+impl TryFrom<Value> for i64 {
+    type Error = String;
+
+    fn try_from(val: Value) -> Result<i64, String> {
+        match val.value_type {
+            ValueType::Int(int) => Ok(int),
+            ValueType::Number(number) => Value::exact_int(number).ok_or_else(|| "Expected an integer.".to_string()),
+            _ => Err("Expected an integer.".to_string()),
+        }
+    }
 }
 
+// objects only know their handle here, use Heap::format_value to see what's inside
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let val = self.value_type.clone();
-        match val {
+        match self.value_type {
+            // whole floats keep a `.0`, so they never print the same as an int
+            ValueType::Number(v) if v.is_finite() && v.fract() == 0.0 => write!(f, "{}.0", v),
             ValueType::Number(v) => write!(f, "{}", v),
+            ValueType::Int(v) => write!(f, "{}", v),
             ValueType::Bool(v) => write!(f, "{}", v),
             ValueType::Nil => write!(f, "Nil"),
-            ValueType::String(s) => write!(f, "\"{}\"", s),
+            ValueType::String(handle) => write!(f, "<string {}>", handle),
+            ValueType::Function(handle) => write!(f, "<fn {}>", handle),
+            ValueType::Closure(handle) => write!(f, "<closure {}>", handle),
+            ValueType::Class(handle) => write!(f, "<class {}>", handle),
+            ValueType::Instance(handle) => write!(f, "<instance {}>", handle),
+            ValueType::BoundMethod(handle) => write!(f, "<bound method {}>", handle),
+            ValueType::Native(handle) => write!(f, "<native fn {}>", handle),
+            ValueType::List(handle) => write!(f, "<list {}>", handle),
+            ValueType::Map(handle) => write!(f, "<map {}>", handle),
         }
     }
 }
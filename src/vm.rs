@@ -1,91 +1,578 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::rc::{Rc, Weak};
 
-use crate::chunk::{Chunk, OpCode};
-use crate::value::Value;
+use crate::chunk::OpCode;
+use crate::diagnostic::{Diagnostic, ErrorCode, TraceFrame};
+use crate::memory::Heap;
+use crate::natives;
+use crate::object::{BoundMethod, Class, Closure, Instance, Map, MapKey, Native, NativeFn, Obj, ObjRef, Upvalue};
+use crate::serialize;
+use crate::value::{Value, ValueType};
 use crate::compiler::Compiler;
 
-pub struct VM {
-    chunk: Chunk,
+const FRAMES_MAX: usize = 64;
+
+struct CallFrame {
+    closure: ObjRef,
+    // the closure's function, kept here so reading code doesn't go through the closure
+    function: ObjRef,
     ip: usize,
+    // index into the VM stack where this frame's locals begin
+    // slot zero holds the function being called
+    slot_base: usize,
+}
+
+pub struct VM {
+    heap: Heap,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    globals: HashMap<String, Value>,
+    globals: HashMap<ObjRef, Value>,
+    // looked up on every class call, so intern it once up front
+    init_string: ObjRef,
+    // upvalues still pointing at live stack slots, so closures capturing
+    // the same variable share a single upvalue
+    open_upvalues: Vec<ObjRef>,
+    // values the host is holding on to, marked for as long as any clone of their Root lives
+    roots: Vec<Weak<Value>>,
+
+    // where `print` writes, where diagnostics and gc logs go, and where scripts read input from
+    // they're the process streams unless the host swaps them out
+    output: Box<dyn Write>,
+    error_output: Box<dyn Write>,
+    input: Box<dyn BufRead>,
+}
+
+// keeps a value and everything it refers to alive across collections
+//
+// object values handed to natives or made with new_string, new_list and new_map are only
+// safe to use until the native returns, or until the next interpret when there's no native
+// running. the collector can reclaim them after that, so a host storing one, in a native's
+// captured state for example, has to root it with VM::root and read it back with `value`
+// dropping the last clone of a Root lets the collector have the value again
+#[derive(Clone)]
+pub struct Root {
+    value: Rc<Value>,
 }
 
+impl Root {
+    pub fn value(&self) -> Value {
+        *self.value
+    }
+}
+
+// errors carry diagnostics instead of being printed, reporting them is up to the caller
 pub enum InterpretResult {
     Ok, // Ok is reserved
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(Diagnostic),
+}
+
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
-        VM {
-            chunk: Chunk::new_chunk(),
-            ip: 0, 
+        VM::with_streams(Box::new(io::stdout()), Box::new(io::stderr()), Box::new(io::BufReader::new(io::stdin())))
+    }
+
+    pub fn with_streams(output: Box<dyn Write>, error_output: Box<dyn Write>, input: Box<dyn BufRead>) -> VM {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let mut vm = VM {
+            heap,
+            frames: Vec::new(),
             stack: Vec::new(),
             globals: HashMap::new(),
+            init_string,
+            open_upvalues: Vec::new(),
+            roots: Vec::new(),
+            output,
+            error_output,
+            input,
+        };
+
+        natives::define_builtins(&mut vm);
+        vm
+    }
+
+    // exposes a rust function or closure to scripts as a global named `name`
+    // redefining an existing global replaces it
+    //
+    //     let calls = Rc::new(Cell::new(0));
+    //     let counter = calls.clone();
+    //     vm.define_native("tick", 0, move |_vm, _args| {
+    //         counter.set(counter.get() + 1);
+    //         Ok(Value::nil_val())
+    //     });
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F) -> ()
+    where
+        F: Fn(&mut VM, &[Value]) -> Result<Value, String> + 'static,
+    {
+        let native = Native {
+            name: name.to_string(),
+            arity,
+            function: Rc::new(function),
+        };
+        let native = self.heap.alloc(Obj::Native(native));
+        let name = self.heap.intern(name);
+        self.globals.insert(name, Value::native_val(native));
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) -> () {
+        self.output = output;
+    }
+
+    pub fn set_error_output(&mut self, error_output: Box<dyn Write>) -> () {
+        self.error_output = error_output;
+    }
+
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) -> () {
+        self.input = input;
+    }
+
+    // the next line of input without its line ending, None once the input runs out
+    pub fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    // writes the diagnostics in `result` to the error output, nothing for a success
+    // pass the source the result came from to get the offending line underlined
+    pub fn report(&mut self, result: &InterpretResult, source: Option<&str>) -> io::Result<()> {
+        let diagnostics: &[Diagnostic] = match result {
+            InterpretResult::Ok => &[],
+            InterpretResult::CompileError(diagnostics) => diagnostics,
+            InterpretResult::RuntimeError(diagnostic) => std::slice::from_ref(diagnostic),
+        };
+
+        for diagnostic in diagnostics {
+            match source {
+                Some(source) => writeln!(self.error_output, "{}", diagnostic.render(source))?,
+                None => writeln!(self.error_output, "{}", diagnostic)?,
+            }
+        }
+        self.error_output.flush()
+    }
+
+    // collect before every instruction, for flushing out objects that aren't rooted properly
+    pub fn set_gc_stress(&mut self, enabled: bool) -> () {
+        self.heap.stress_gc = enabled;
+    }
+
+    // print a summary of every collection to the error output
+    pub fn set_gc_log(&mut self, enabled: bool) -> () {
+        self.heap.log_gc = enabled;
+    }
+
+    // strings live on the VM heap, so natives go through here to make them
+    // like every object value, the result needs rooting to outlive the native, see Root
+    pub fn new_string(&mut self, string: &str) -> Value {
+        Value::string_val(self.heap.intern(string))
+    }
+
+    // the contents of a string value, None for anything else
+    pub fn as_str(&self, value: Value) -> Option<&str> {
+        if Value::is_string(&value) {
+            Some(self.heap.string(Value::as_string(value)))
+        } else {
+            None
+        }
+    }
+
+    pub fn new_list(&mut self, items: Vec<Value>) -> Value {
+        Value::list_val(self.heap.alloc(Obj::List(items)))
+    }
+
+    // the items of a list value, None for anything else
+    pub fn as_list(&self, value: Value) -> Option<&Vec<Value>> {
+        if Value::is_list(&value) {
+            Some(self.heap.list(Value::as_list(value)))
+        } else {
+            None
+        }
+    }
+
+    // growth from inside a native counts toward the next collection once the native returns
+    pub fn as_list_mut(&mut self, value: Value) -> Option<&mut Vec<Value>> {
+        if Value::is_list(&value) {
+            Some(self.heap.list_mut(Value::as_list(value)))
+        } else {
+            None
+        }
+    }
+
+    pub fn new_map(&mut self, map: Map) -> Value {
+        Value::map_val(self.heap.alloc(Obj::Map(map)))
+    }
+
+    // the entries of a map value, None for anything else
+    pub fn as_map(&self, value: Value) -> Option<&Map> {
+        if Value::is_map(&value) {
+            Some(self.heap.map(Value::as_map(value)))
+        } else {
+            None
+        }
+    }
+
+    // like as_list_mut, growth from inside a native is counted once it returns
+    pub fn as_map_mut(&mut self, value: Value) -> Option<&mut Map> {
+        if Value::is_map(&value) {
+            Some(self.heap.map_mut(Value::as_map(value)))
+        } else {
+            None
         }
     }
 
-    pub fn interpret(&mut self, source: &String) -> InterpretResult {
-        self.chunk = Chunk::new_chunk();
-        self.reset_stack(); // we need to reset pointer to start of char vector
-        let mut compiler = Compiler::new(&mut self.chunk);
+    // roots `value` for as long as the returned Root, or a clone of it, is alive
+    // the Root only means something to this VM
+    pub fn root(&mut self, value: Value) -> Root {
+        let value = Rc::new(value);
+        self.roots.push(Rc::downgrade(&value));
+        Root { value }
+    }
+
+    // a value the way `print` would show it
+    pub fn format_value(&self, value: Value) -> String {
+        self.heap.format_value(&value)
+    }
+
+    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        self.interpret_named(source, "<script>")
+    }
+
+    // like interpret, but stack traces say the code came from `name`
+    pub fn interpret_named(&mut self, source: &str, name: &str) -> InterpretResult {
+        self.reset_stack();
+        let mut compiler = Compiler::new(&mut self.heap);
+
+        let function = match compiler.compile(source, name) {
+            Ok(function) => function,
+            Err(diagnostics) => return InterpretResult::CompileError(diagnostics),
+        };
+
+        self.run_script(function)
+    }
+
+    // compiles a script into the .loxc format without running it
+    pub fn compile_to_bytecode(&mut self, source: &str, name: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        let mut compiler = Compiler::new(&mut self.heap);
+        let function = compiler.compile(source, name)?;
+        Ok(serialize::serialize(function, &self.heap))
+    }
+
+    // runs a script previously written out by compile_to_bytecode
+    // files from another format version are refused rather than guessed at
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> InterpretResult {
+        self.reset_stack();
+        let function = match serialize::deserialize(bytes, &mut self.heap) {
+            Ok(function) => function,
+            Err(msg) => {
+                let diagnostic = Diagnostic::error(ErrorCode::InvalidBytecode, &msg);
+                return InterpretResult::CompileError(vec![diagnostic]);
+            }
+        };
+
+        self.run_script(function)
+    }
 
-        if !compiler.compile(&source) {
-            return InterpretResult::CompileError
+    fn run_script(&mut self, function: ObjRef) -> InterpretResult {
+        // the top level script is called like any other zero-argument function
+        let closure = self.heap.alloc(Obj::Closure(Closure::new(function)));
+        self.stack.push(Value::closure_val(closure));
+        if let Err(error) = self.call(closure, 0) {
+            return error;
         }
 
-        self.run()
+        let result = self.run();
+        // hosts buffering the output still see everything printed before an error
+        let _ = self.output.flush();
+        result
     }
-    
-    pub fn run(&mut self) -> InterpretResult {
+
+    // only run_script may start this, it relies on the script's frame being set up
+    fn run(&mut self) -> InterpretResult {
         loop {
-            let ip = self.ip;
-            self.ip += 1;
-            // handle overflow
-            if ip >= self.chunk.code.len() {
-                return InterpretResult::Ok
+            // only collect between instructions, when everything live is reachable from a root
+            if self.heap.should_collect() {
+                self.collect_garbage();
             }
 
-            let instruction = self.chunk.code[ip].code.clone();
+            let byte = self.read_byte();
+            let instruction = match OpCode::from_byte(byte) {
+                Some(op) => op,
+                None => {
+                    break self.runtime_error(ErrorCode::InvalidBytecode, &format!("Unknown opcode {}", byte))
+                }
+            };
+
             match instruction {
-                OpCode::Return => (),
-                /*
-                 * OpCode::Return => {
-                    match self.stack.pop() {
-                        Some(val) => {
-                            println!("{}", val);
-                            break InterpretResult::Ok
+                OpCode::Return => {
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+                    // any locals closed over by this frame are about to disappear
+                    self.close_upvalues(frame.slot_base);
+                    if self.frames.is_empty() {
+                        // pop the script function itself
+                        self.stack.pop();
+                        break InterpretResult::Ok
+                    }
+
+                    // throw away the callee and its arguments and locals
+                    self.stack.truncate(frame.slot_base);
+                    self.stack.push(result);
+                },
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = *self.peek(arg_count);
+                    if let Err(error) = self.call_value(callee, arg_count) {
+                        break error
+                    }
+                },
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let function = Value::as_function(self.read_constant(instruction.is_long()));
+                    let mut closure = Closure::new(function);
+                    for _ in 0..self.heap.function(function).upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            let slot = self.frame().slot_base + index;
+                            self.capture_upvalue(slot)
+                        } else {
+                            self.heap.closure(self.frame().closure).upvalues[index]
+                        };
+                        closure.upvalues.push(upvalue);
+                    }
+                    let closure = self.heap.alloc(Obj::Closure(closure));
+                    self.stack.push(Value::closure_val(closure));
+                },
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.heap.closure(self.frame().closure).upvalues[index];
+                    let value = match self.heap.upvalue(upvalue) {
+                        Upvalue::Open(slot) => self.stack[*slot],
+                        Upvalue::Closed(value) => *value,
+                    };
+                    self.stack.push(value);
+                },
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.heap.closure(self.frame().closure).upvalues[index];
+                    let value = *self.peek(0);
+                    match self.heap.upvalue_mut(upvalue) {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                },
+                OpCode::CloseUpvalue => {
+                    let top = self.stack.len() - 1;
+                    self.close_upvalues(top);
+                    self.stack.pop();
+                },
+                OpCode::Class | OpCode::ClassLong => {
+                    let name = self.read_string(instruction.is_long());
+                    let class = self.heap.alloc(Obj::Class(Class::new(name)));
+                    self.stack.push(Value::class_val(class));
+                },
+                OpCode::Method | OpCode::MethodLong => {
+                    let name = self.read_string(instruction.is_long());
+                    // the closure sits on top of the class it belongs to
+                    let method = *self.peek(0);
+                    let class = Value::as_class(*self.peek(1));
+                    self.heap.class_mut(class).methods.insert(name, method);
+                    self.heap.remeasure(class);
+                    self.stack.pop();
+                },
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let name = self.read_string(instruction.is_long());
+                    if !Value::is_instance(self.peek(0)) {
+                        break self.runtime_error(ErrorCode::Type, "Only instances have properties.")
+                    }
+
+                    let instance = Value::as_instance(*self.peek(0));
+                    let field = self.heap.instance(instance).fields.get(&name).copied();
+                    match field {
+                        // fields shadow methods
+                        Some(value) => {
+                            self.stack.pop();
+                            self.stack.push(value);
                         },
                         None => {
-                            println!("Error: Nothing to return");
-                            break InterpretResult::CompileError
+                            let class = self.heap.instance(instance).class;
+                            if let Err(error) = self.bind_method(class, &name) {
+                                break error
+                            }
+                        }
+                    }
+                },
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let name = self.read_string(instruction.is_long());
+                    if !Value::is_instance(self.peek(1)) {
+                        break self.runtime_error(ErrorCode::Type, "Only instances have fields.")
+                    }
+
+                    let value = self.stack.pop().unwrap();
+                    let instance = Value::as_instance(self.stack.pop().unwrap());
+                    self.heap.instance_mut(instance).fields.insert(name, value);
+                    self.heap.remeasure(instance);
+                    // assignment is an expression, leave the value behind
+                    self.stack.push(value);
+                },
+                OpCode::Dup => {
+                    let depth = self.read_byte() as usize;
+                    let value = self.stack[self.stack.len() - 1 - depth];
+                    self.stack.push(value);
+                },
+                OpCode::Bury => {
+                    let depth = self.read_byte() as usize;
+                    let value = self.stack.pop().unwrap();
+                    self.stack.insert(self.stack.len() - depth, value);
+                },
+                OpCode::BuildList => {
+                    let count = self.read_byte() as usize;
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    let list = self.heap.alloc(Obj::List(items));
+                    self.stack.push(Value::list_val(list));
+                },
+                OpCode::BuildMap => {
+                    let count = self.read_byte() as usize;
+                    let entries = self.stack.split_off(self.stack.len() - 2 * count);
+                    let mut map = Map::new();
+                    let mut bad_key = None;
+                    for entry in entries.chunks(2) {
+                        match MapKey::from_value(&entry[0]) {
+                            Ok(key) => map.insert(key, entry[1]),
+                            Err(msg) => {
+                                bad_key = Some(msg);
+                                break;
+                            }
                         }
                     }
+                    if let Some(msg) = bad_key {
+                        break self.runtime_error(ErrorCode::Type, &msg)
+                    }
+
+                    let map = self.heap.alloc(Obj::Map(map));
+                    self.stack.push(Value::map_val(map));
+                },
+                OpCode::Stringify => {
+                    let value = *self.peek(0);
+                    if !Value::is_string(&value) {
+                        let text = self.heap.format_value(&value);
+                        let string = self.heap.intern(&text);
+                        self.stack.pop();
+                        self.stack.push(Value::string_val(string));
+                    }
+                },
+                OpCode::GetIndex => {
+                    let index = self.stack.pop().unwrap();
+                    let target = self.stack.pop().unwrap();
+                    match self.get_index(target, index) {
+                        Ok(value) => self.stack.push(value),
+                        Err(error) => break error,
+                    }
+                },
+                OpCode::SetIndex => {
+                    let value = self.stack.pop().unwrap();
+                    let index = self.stack.pop().unwrap();
+                    let target = self.stack.pop().unwrap();
+                    if let Err(error) = self.set_index(target, index, value) {
+                        break error
+                    }
+                    // assignment is an expression, leave the value behind
+                    self.stack.push(value);
+                },
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let name = self.read_string(instruction.is_long());
+                    let arg_count = self.read_byte() as usize;
+                    if let Err(error) = self.invoke(&name, arg_count) {
+                        break error
+                    }
+                },
+                OpCode::Inherit => {
+                    if !Value::is_class(self.peek(1)) {
+                        break self.runtime_error(ErrorCode::Type, "Superclass must be a class.")
+                    }
+
+                    // copy-down inheritance: the subclass starts with every superclass method
+                    // and anything it declares afterwards overrides them
+                    let superclass = Value::as_class(*self.peek(1));
+                    let subclass = Value::as_class(*self.peek(0));
+                    let methods = self.heap.class(superclass).methods.clone();
+                    self.heap.class_mut(subclass).methods.extend(methods);
+                    self.heap.remeasure(subclass);
+                    self.stack.pop();
+                },
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = self.read_string(instruction.is_long());
+                    let superclass = Value::as_class(self.stack.pop().unwrap());
+                    if let Err(error) = self.bind_method(superclass, &name) {
+                        break error
+                    }
+                },
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let name = self.read_string(instruction.is_long());
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = Value::as_class(self.stack.pop().unwrap());
+                    if let Err(error) = self.invoke_from_class(superclass, &name, arg_count) {
+                        break error
+                    }
+                },
+                OpCode::Print => {
+                    let value = self.stack.pop().unwrap();
+                    let text = self.heap.format_value(&value);
+                    if let Err(error) = writeln!(self.output, "{}", text) {
+                        break self.runtime_error(ErrorCode::Io, &format!("Could not write output: {}.", error));
+                    }
+                },
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let value = self.read_constant(instruction.is_long());
+                    self.stack.push(value);
                 },
-                */
-                OpCode::Print => println!("{}", self.stack.pop().unwrap()),
-                OpCode::Constant(val) => self.stack.push(val),
                 OpCode::Nil => self.stack.push(Value::nil_val()),
                 OpCode::True => self.stack.push(Value::bool_val(true)),
                 OpCode::False => self.stack.push(Value::bool_val(false)),
                 OpCode::Negate => {
-                    match &self.peek(0) {
-                        val if !Value::is_number(&val) => {
-                            self.runtime_error("Operand must be a number");
-                            break InterpretResult::RuntimeError
+                    match self.peek(0).value_type {
+                        ValueType::Number(number) => {
+                            self.stack.pop();
+                            self.stack.push(Value::number_val(-number));
                         },
-                        _ => match self.stack.pop() {
-                                Some(val) => self.stack.push(Value::number_val(Value::as_number(val) * -1.0)),
-                                None => panic!("Error - no value to pop from stack"),
-                            }
+                        ValueType::Int(int) => match int.checked_neg() {
+                            Some(negated) => {
+                                self.stack.pop();
+                                self.stack.push(Value::int_val(negated));
+                            },
+                            None => break self.runtime_error(ErrorCode::Arithmetic, "Integer overflow."),
+                        },
+                        _ => break self.runtime_error(ErrorCode::Type, "Operand must be a number"),
+                    }
+                },
+                OpCode::BitNot => {
+                    match self.peek(0).value_type {
+                        ValueType::Int(int) => {
+                            self.stack.pop();
+                            self.stack.push(Value::int_val(!int));
+                        },
+                        _ => break self.runtime_error(ErrorCode::Type, "Operand must be an integer"),
                     }
                 },
                 OpCode::Not => match self.stack.pop() {
                     None => {
-                        self.runtime_error("No value to pop from stack");
-                        break InterpretResult::RuntimeError
+                        break self.runtime_error(ErrorCode::InvalidBytecode, "No value to pop from stack")
                     },
                     Some(val) => self.stack.push(Value::bool_val(Value::is_falsey(&val))),
                 },
@@ -98,69 +585,86 @@ impl VM {
                 OpCode::Pop => {
                     self.stack.pop();
                 },
-                OpCode::DefineGlobal(val) => {
-                    self.globals.insert(val, self.peek(0).clone());
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let name = self.read_string(instruction.is_long());
+                    self.globals.insert(name, *self.peek(0));
                     self.stack.pop();
                 }
-                OpCode::GetGlobal(val) => {
-                    let value = self.globals.get(&val);
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let name = self.read_string(instruction.is_long());
+                    let value = self.globals.get(&name);
                     if let Some(v) = value {
-                        self.stack.push(v.clone());
+                        self.stack.push(*v);
                     } else {
-                        self.runtime_error(&format!("Undefined variable {}", val));
-                        return InterpretResult::RuntimeError;
+                        let name = self.heap.string(name).clone();
+                        return self.runtime_error(ErrorCode::UndefinedVariable, &format!("Undefined variable {}", name));
                     }
                 }
 
-                OpCode::SetGlobal(val) => {
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let name = self.read_string(instruction.is_long());
                     // setting a variable that's been previously declared
                     // first make sure the variable exists
-                    if self.globals.contains_key(&val) {
-                        self.globals.insert(val, self.peek(0).clone());
+                    if self.globals.contains_key(&name) {
+                        self.globals.insert(name, *self.peek(0));
                     } else {
-                        self.runtime_error(&format!("Undefined variable {}", val));
-                        return InterpretResult::RuntimeError;
+                        let name = self.heap.string(name).clone();
+                        return self.runtime_error(ErrorCode::UndefinedVariable, &format!("Undefined variable {}", name));
                     }
                 }
 
-                OpCode::GetLocal(val) => {
-                    self.stack.push(self.stack[val].clone());
+                OpCode::GetLocal => {
+                    let slot = self.frame().slot_base + self.read_byte() as usize;
+                    self.stack.push(self.stack[slot]);
                 }
 
-                OpCode::SetLocal(val) => {
-                    self.stack[val] = self.peek(0).clone();
+                OpCode::SetLocal => {
+                    let slot = self.frame().slot_base + self.read_byte() as usize;
+                    self.stack[slot] = *self.peek(0);
                 }
                 
-                OpCode::JumpIfFalse(val) => {
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_u16() as usize;
                     if Value::is_falsey(self.peek(0)) {
-                        self.ip += val
+                        self.frame_mut().ip += offset
                     };    
                 }
                 
-                OpCode::Jump(val) => {
-                    self.ip += val;
+                OpCode::Jump => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip += offset;
                 }
 
-                OpCode::Loop(val) => {
-                    self.ip -= val;
-                    /* for _ in 0..val {
-                        self.stack.pop();
-                    } */
+                OpCode::Loop => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip -= offset;
                 }
                 // todo - consolidate with binary_operation
                 OpCode::Greater | OpCode::Less => {
-                    match self.binary_comparison(&instruction) {
-                        Err(error) => break error,
-                        _ => ()
+                    if let Err(error) = self.binary_comparison(&instruction) {
+                        break error
                     }
                 }
 
                 OpCode::Add | 
                     OpCode::Subtract |
                     OpCode:: Multiply | 
-                    OpCode:: Divide => match self.binary_operation(&instruction) {
-                        Err(error) => break error,
-                        _ => (),
+                    OpCode:: Divide |
+                    OpCode::Modulo |
+                    OpCode::Power => {
+                        if let Err(error) = self.binary_operation(&instruction) {
+                            break error
+                        }
+                    }
+
+                OpCode::BitAnd |
+                    OpCode::BitOr |
+                    OpCode::BitXor |
+                    OpCode::ShiftLeft |
+                    OpCode::ShiftRight => {
+                        if let Err(error) = self.bitwise_operation(&instruction) {
+                            break error
+                        }
                     }
 
             }
@@ -171,68 +675,457 @@ impl VM {
         &self.stack[self.stack.len() - 1 - distance]
     }
 
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frames.last_mut().unwrap();
+        let byte = self.heap.function(frame.function).chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let frame = self.frames.last_mut().unwrap();
+        let value = self.heap.function(frame.function).chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    // reads a constant index operand, three bytes wide for the long instructions
+    fn read_constant(&mut self, long: bool) -> Value {
+        let frame = self.frames.last_mut().unwrap();
+        let chunk = &self.heap.function(frame.function).chunk;
+        let index = if long {
+            frame.ip += 3;
+            chunk.read_u24(frame.ip - 3)
+        } else {
+            frame.ip += 1;
+            chunk.code[frame.ip - 1] as usize
+        };
+        chunk.constants[index]
+    }
+
+    fn read_string(&mut self, long: bool) -> ObjRef {
+        Value::as_string(self.read_constant(long))
+    }
+
+    fn collect_garbage(&mut self) -> () {
+        let before = self.heap.bytes_allocated();
+        if self.heap.log_gc {
+            let _ = writeln!(self.error_output, "-- gc begin");
+        }
+
+        // roots: anything the running program can still reach directly
+        for value in self.stack.iter() {
+            self.heap.mark_value(value);
+        }
+        for frame in self.frames.iter() {
+            self.heap.mark_object(frame.closure);
+        }
+        for upvalue in self.open_upvalues.iter() {
+            self.heap.mark_object(*upvalue);
+        }
+        for (name, value) in self.globals.iter() {
+            self.heap.mark_object(*name);
+            self.heap.mark_value(value);
+        }
+        self.heap.mark_object(self.init_string);
+        self.roots.retain(|root| root.strong_count() > 0);
+        for root in self.roots.iter() {
+            if let Some(value) = root.upgrade() {
+                self.heap.mark_value(&value);
+            }
+        }
+
+        self.heap.trace_references();
+        self.heap.sweep();
+
+        if self.heap.log_gc {
+            let after = self.heap.bytes_allocated();
+            let _ = writeln!(self.error_output, "-- gc end");
+            let _ = writeln!(self.error_output, "   collected {} bytes (from {} to {}) next at {}", before - after, before, after, self.heap.next_gc());
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretResult> {
+        let callee_slot = self.stack.len() - arg_count - 1;
+        if Value::is_closure(&callee) {
+            self.call(Value::as_closure(callee), arg_count)
+        } else if Value::is_native(&callee) {
+            self.call_native(Value::as_native(callee), arg_count)
+        } else if Value::is_bound_method(&callee) {
+            // the receiver takes the callee's slot so the method sees it as `this`
+            let bound = self.heap.bound_method(Value::as_bound_method(callee));
+            let method = bound.method;
+            self.stack[callee_slot] = bound.receiver;
+            self.call(method, arg_count)
+        } else if Value::is_class(&callee) {
+            // calling a class makes a new instance, which replaces the class on the stack
+            let class = Value::as_class(callee);
+            let instance = self.heap.alloc(Obj::Instance(Instance::new(class)));
+            self.stack[callee_slot] = Value::instance_val(instance);
+
+            let initializer = self.heap.class(class).methods.get(&self.init_string).copied();
+            match initializer {
+                Some(init) => self.call(Value::as_closure(init), arg_count),
+                None if arg_count != 0 => {
+                    Err(self.runtime_error(ErrorCode::Arity, &format!("Expected 0 arguments but got {}.", arg_count)))
+                },
+                None => Ok(()),
+            }
+        } else {
+            Err(self.runtime_error(ErrorCode::Type, "Can only call functions and classes."))
+        }
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), InterpretResult> {
+        let arity = self.heap.function(self.heap.closure(closure).function).arity;
+        if arg_count != arity {
+            return Err(self.runtime_error(ErrorCode::Arity, &format!("Expected {} arguments but got {}.", arity, arg_count)));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error(ErrorCode::StackOverflow, "Stack overflow."));
+        }
+
+        let slot_base = self.stack.len() - arg_count - 1;
+        let function = self.heap.closure(closure).function;
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slot_base,
+        });
+        Ok(())
+    }
+
+    fn call_native(&mut self, native: ObjRef, arg_count: usize) -> Result<(), InterpretResult> {
+        let native = self.heap.native(native);
+        // cloning the handle lets the native borrow the VM mutably while it runs
+        let (arity, function): (usize, NativeFn) = (native.arity, native.function.clone());
+        if arg_count != arity {
+            return Err(self.runtime_error(ErrorCode::Arity, &format!("Expected {} arguments but got {}.", arity, arg_count)));
+        }
+
+        // natives get their own copy of the arguments so they're free to use the VM
+        let args_start = self.stack.len() - arg_count;
+        let args: Vec<Value> = self.stack[args_start..].to_vec();
+        let result = function(self, &args);
+        // natives like push() grow the collections they're given
+        for arg in args.iter() {
+            if let Some(handle) = Value::as_obj_ref(arg) {
+                self.heap.remeasure(handle);
+            }
+        }
+
+        match result {
+            Ok(result) => {
+                // drop the arguments and the native itself
+                self.stack.truncate(args_start - 1);
+                self.stack.push(result);
+                Ok(())
+            },
+            Err(msg) => {
+                Err(self.runtime_error(ErrorCode::Native, &msg))
+            }
+        }
+    }
+
+    fn invoke(&mut self, name: &ObjRef, arg_count: usize) -> Result<(), InterpretResult> {
+        let receiver = *self.peek(arg_count);
+        if !Value::is_instance(&receiver) {
+            return Err(self.runtime_error(ErrorCode::Type, "Only instances have methods."));
+        }
+
+        let instance = Value::as_instance(receiver);
+        // a field holding a function is called like any other value
+        let field = self.heap.instance(instance).fields.get(name).copied();
+        if let Some(value) = field {
+            let callee_slot = self.stack.len() - arg_count - 1;
+            self.stack[callee_slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        let class = self.heap.instance(instance).class;
+        self.invoke_from_class(class, name, arg_count)
+    }
+
+    fn invoke_from_class(&mut self, class: ObjRef, name: &ObjRef, arg_count: usize) -> Result<(), InterpretResult> {
+        let method = self.heap.class(class).methods.get(name).copied();
+        match method {
+            Some(method) => self.call(Value::as_closure(method), arg_count),
+            None => {
+                let name = self.heap.string(*name).clone();
+                Err(self.runtime_error(ErrorCode::UndefinedProperty, &format!("Undefined property '{}'.", name)))
+            }
+        }
+    }
+
+    // replaces the instance on top of the stack with its method `name` bound to it
+    fn bind_method(&mut self, class: ObjRef, name: &ObjRef) -> Result<(), InterpretResult> {
+        let method = self.heap.class(class).methods.get(name).copied();
+        match method {
+            Some(method) => {
+                let bound = BoundMethod {
+                    receiver: *self.peek(0),
+                    method: Value::as_closure(method),
+                };
+                let bound = self.heap.alloc(Obj::BoundMethod(bound));
+                self.stack.pop();
+                self.stack.push(Value::bound_method_val(bound));
+                Ok(())
+            },
+            None => {
+                let name = self.heap.string(*name).clone();
+                Err(self.runtime_error(ErrorCode::UndefinedProperty, &format!("Undefined property '{}'.", name)))
+            }
+        }
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        for upvalue in self.open_upvalues.iter() {
+            if let Upvalue::Open(open_slot) = self.heap.upvalue(*upvalue) {
+                if *open_slot == slot {
+                    return *upvalue;
+                }
+            }
+        }
+
+        let upvalue = self.heap.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    // hoists every open upvalue at or above `last` off the stack and into the upvalue itself
+    fn close_upvalues(&mut self, last: usize) -> () {
+        let stack = &self.stack;
+        let heap = &mut self.heap;
+        self.open_upvalues.retain(|upvalue| {
+            let upvalue = heap.upvalue_mut(*upvalue);
+            let slot = match upvalue {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) => return false,
+            };
+
+            if slot >= last {
+                *upvalue = Upvalue::Closed(stack[slot]);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
     fn binary_operation(&mut self, operator: &OpCode) -> Result<(), InterpretResult> {
         // handle string concat separately
-        if Value::is_string(&self.peek(0)) && Value::is_string(&self.peek(1)) {
+        if Value::is_string(self.peek(0)) && Value::is_string(self.peek(1)) {
             match operator {
                 OpCode::Add => {
                     self.concatenate();
                     Ok(())
                 },
                 _ => {
-                    self.runtime_error("Invalid operator for strings");
-                    Err(InterpretResult::RuntimeError)
+                    Err(self.runtime_error(ErrorCode::Type, "Invalid operator for strings"))
                 }
             }
-        } else if Value::is_number(&self.peek(0)) && Value::is_number(&self.peek(0)) {
-            let b = Value::as_number(self.stack.pop().unwrap());
-            let a = Value::as_number(self.stack.pop().unwrap());
+        } else if Value::is_int(self.peek(0)) && Value::is_int(self.peek(1)) {
+            self.int_operation(operator)
+        } else if Value::is_numeric(self.peek(0)) && Value::is_numeric(self.peek(1)) {
+            // mixing an int with a float promotes the int
+            let b = Value::to_float(&self.stack.pop().unwrap()).unwrap();
+            let a = Value::to_float(&self.stack.pop().unwrap()).unwrap();
             match operator {
                 OpCode::Add => self.stack.push(Value::number_val(a + b)),
                 OpCode::Subtract => self.stack.push(Value::number_val(a - b)),
                 OpCode::Multiply => self.stack.push(Value::number_val(a * b)),
                 OpCode::Divide => self.stack.push(Value::number_val(a / b)),
-                _ => panic!("{} is not a binary operation", operator),
+                OpCode::Modulo => self.stack.push(Value::number_val(a % b)),
+                OpCode::Power => self.stack.push(Value::number_val(a.powf(b))),
+                _ => panic!("{:?} is not a binary operation", operator),
             }
             Ok(())
         } else {
-            self.runtime_error("Operands must be both numbers or strings");
-            Err(InterpretResult::RuntimeError)
+            Err(self.runtime_error(ErrorCode::Type, "Operands must be both numbers or strings"))
+        }
+    }
+
+    // ints stay ints, except that dividing always gives a float so `7 / 2` is 3.5
+    // a result that doesn't fit in an i64 is an error rather than wrapping around
+    fn int_operation(&mut self, operator: &OpCode) -> Result<(), InterpretResult> {
+        let b = Value::as_int(self.stack.pop().unwrap());
+        let a = Value::as_int(self.stack.pop().unwrap());
+        let result = match operator {
+            OpCode::Add => a.checked_add(b),
+            OpCode::Subtract => a.checked_sub(b),
+            OpCode::Multiply => a.checked_mul(b),
+            // dividing ints gives a float, but like `%` refuses a zero divisor
+            // rather than quietly giving inf or nan
+            OpCode::Divide if b == 0 => return Err(self.runtime_error(ErrorCode::Arithmetic, "Division by zero.")),
+            OpCode::Divide => {
+                self.stack.push(Value::number_val(a as f64 / b as f64));
+                return Ok(());
+            },
+            // the remainder takes the sign of the left operand, as in C
+            OpCode::Modulo if b == 0 => return Err(self.runtime_error(ErrorCode::Arithmetic, "Division by zero.")),
+            // only i64::MIN % -1 wraps, and its remainder really is 0
+            OpCode::Modulo => Some(a.wrapping_rem(b)),
+            // a negative exponent gives a fraction
+            OpCode::Power if b < 0 => {
+                self.stack.push(Value::number_val((a as f64).powf(b as f64)));
+                return Ok(());
+            },
+            OpCode::Power if b <= u32::MAX as i64 => a.checked_pow(b as u32),
+            // only 0, 1 and -1 can be raised that high without overflowing
+            OpCode::Power => match a {
+                0 | 1 => Some(a),
+                -1 => Some(if b % 2 == 0 { 1 } else { -1 }),
+                _ => None,
+            },
+            _ => panic!("{:?} is not a binary operation", operator),
+        };
+
+        match result {
+            Some(result) => {
+                self.stack.push(Value::int_val(result));
+                Ok(())
+            },
+            None => Err(self.runtime_error(ErrorCode::Arithmetic, "Integer overflow.")),
+        }
+    }
+
+    // bitwise operators only work on ints
+    // shifting keeps the sign on the way right, and drops bits shifted out of either end
+    fn bitwise_operation(&mut self, operator: &OpCode) -> Result<(), InterpretResult> {
+        if !Value::is_int(self.peek(0)) || !Value::is_int(self.peek(1)) {
+            return Err(self.runtime_error(ErrorCode::Type, "Operands must be integers"));
+        }
+
+        let b = Value::as_int(self.stack.pop().unwrap());
+        let a = Value::as_int(self.stack.pop().unwrap());
+        let result = match operator {
+            OpCode::BitAnd => a & b,
+            OpCode::BitOr => a | b,
+            OpCode::BitXor => a ^ b,
+            OpCode::ShiftLeft | OpCode::ShiftRight if !(0..64).contains(&b) => {
+                return Err(self.runtime_error(ErrorCode::Arithmetic, "Shift amount must be between 0 and 63."));
+            },
+            OpCode::ShiftLeft => a << b,
+            OpCode::ShiftRight => a >> b,
+            _ => panic!("{:?} is not a bitwise operation", operator),
+        };
+        self.stack.push(Value::int_val(result));
+        Ok(())
+    }
+
+    // `target[index]`, an item of a list or the value stored under a key in a map
+    fn get_index(&mut self, target: Value, index: Value) -> Result<Value, InterpretResult> {
+        if Value::is_list(&target) {
+            let list = Value::as_list(target);
+            match Value::as_index(&index, self.heap.list(list).len()) {
+                Ok(index) => Ok(self.heap.list(list)[index]),
+                Err(msg) => Err(self.runtime_error(ErrorCode::Index, &msg)),
+            }
+        } else if Value::is_map(&target) {
+            let key = match MapKey::from_value(&index) {
+                Ok(key) => key,
+                Err(msg) => return Err(self.runtime_error(ErrorCode::Type, &msg)),
+            };
+            match self.heap.map(Value::as_map(target)).get(&key) {
+                Some(value) => Ok(value),
+                None => {
+                    let msg = format!("Undefined key {}.", self.heap.format_value(&index));
+                    Err(self.runtime_error(ErrorCode::Index, &msg))
+                }
+            }
+        } else {
+            Err(self.runtime_error(ErrorCode::Type, "Only lists and maps can be indexed."))
+        }
+    }
+
+    fn set_index(&mut self, target: Value, index: Value, value: Value) -> Result<(), InterpretResult> {
+        if Value::is_list(&target) {
+            let list = Value::as_list(target);
+            match Value::as_index(&index, self.heap.list(list).len()) {
+                Ok(index) => {
+                    self.heap.list_mut(list)[index] = value;
+                    Ok(())
+                },
+                Err(msg) => Err(self.runtime_error(ErrorCode::Index, &msg)),
+            }
+        } else if Value::is_map(&target) {
+            match MapKey::from_value(&index) {
+                Ok(key) => {
+                    let map = Value::as_map(target);
+                    self.heap.map_mut(map).insert(key, value);
+                    self.heap.remeasure(map);
+                    Ok(())
+                },
+                Err(msg) => Err(self.runtime_error(ErrorCode::Type, &msg)),
+            }
+        } else {
+            Err(self.runtime_error(ErrorCode::Type, "Only lists and maps can be indexed."))
         }
     }
 
     fn concatenate(&mut self) -> () {
         let b = Value::as_string(self.stack.pop().unwrap());
-        let mut a = Value::as_string(self.stack.pop().unwrap());
-        a.push_str(&b);
-        self.stack.push(Value::string_val(a));
+        let a = Value::as_string(self.stack.pop().unwrap());
+        let mut result = self.heap.string(a).clone();
+        result.push_str(self.heap.string(b));
+        let result = self.heap.intern(&result);
+        self.stack.push(Value::string_val(result));
     }
 
     fn binary_comparison(&mut self, operator: &OpCode) -> Result<(), InterpretResult> {
-        if !Value::is_number(&self.peek(0)) || !Value::is_number(&self.peek(0)) {
-            self.runtime_error("Operands must be numbers");
-            Err(InterpretResult::RuntimeError)
-        } else {
-            let b = Value::as_number(self.stack.pop().unwrap());
-            let a = Value::as_number(self.stack.pop().unwrap());
-            match operator {
-                OpCode::Greater => self.stack.push(Value::bool_val(a > b)),
-                OpCode::Less => self.stack.push(Value::bool_val(a < b)),
-                _ => panic!("{} is not a binary comparator", operator),
-            }
-            Ok(())
+        if !Value::is_numeric(self.peek(0)) || !Value::is_numeric(self.peek(1)) {
+            return Err(self.runtime_error(ErrorCode::Type, "Operands must be numbers"));
+        }
+
+        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        let ordering = Value::compare_numbers(a, b);
+        match operator {
+            OpCode::Greater => self.stack.push(Value::bool_val(ordering == Some(Ordering::Greater))),
+            OpCode::Less => self.stack.push(Value::bool_val(ordering == Some(Ordering::Less))),
+            _ => panic!("{:?} is not a binary comparator", operator),
         }
+        Ok(())
     }
 
     fn reset_stack(&mut self) -> () {
-        self.ip = 0;
         self.stack = Vec::new();
+        self.frames = Vec::new();
+        self.open_upvalues = Vec::new();
     }
 
-    fn runtime_error(&mut self, msg: &str) -> () {
-        let line = self.chunk.code[self.ip - 1].line;
-        eprintln!("{}", msg);
-        eprintln!("[line {}] in script", line);
-        self.reset_stack()
+    // unwinds the VM and describes what went wrong, for handing back out of run
+    fn runtime_error(&mut self, code: ErrorCode, msg: &str) -> InterpretResult {
+        // the ip has already moved past the instruction that failed
+        let frame = self.frame();
+        let location = self.heap.function(frame.function).chunk.get_location(frame.ip - 1);
+
+        // every frame's ip sits just past its call instruction, or the failing one for the innermost
+        let trace = self.frames.iter().rev().map(|frame| {
+            let function = self.heap.function(frame.function);
+            TraceFrame {
+                function: function.name.map(|name| self.heap.string(name).clone()),
+                file: self.heap.string(function.file).clone(),
+                line: function.chunk.get_line(frame.ip - 1),
+            }
+        }).collect();
+        self.reset_stack();
+
+        let diagnostic = Diagnostic::error(code, msg)
+            .at_line(location.line)
+            .at_column(location.column)
+            .with_span(location.span)
+            .with_trace(trace);
+        InterpretResult::RuntimeError(diagnostic)
     }
 }
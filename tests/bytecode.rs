@@ -0,0 +1,185 @@
+// compiled .loxc files: they run the same as their source, and damaged or hand-built ones are refused
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use lox_rust::{ErrorCode, InterpretResult, SharedBuffer, VM, FORMAT_VERSION};
+
+const HEADER_SIZE: usize = 4 + 2 + 4;
+
+fn new_vm() -> (VM, SharedBuffer) {
+    let output = SharedBuffer::new();
+    let vm = VM::with_streams(Box::new(output.clone()), Box::new(SharedBuffer::new()), Box::new(std::io::empty()));
+    (vm, output)
+}
+
+fn compile(source: &str) -> Vec<u8> {
+    let (mut vm, _) = new_vm();
+    match vm.compile_to_bytecode(source, "test.lox") {
+        Ok(bytes) => bytes,
+        Err(diagnostics) => panic!("{:?}", diagnostics),
+    }
+}
+
+// the message the file was refused with
+fn rejection(bytes: &[u8]) -> String {
+    let (mut vm, _) = new_vm();
+    match vm.interpret_bytecode(bytes) {
+        InterpretResult::CompileError(diagnostics) => {
+            assert_eq!(diagnostics[0].code, ErrorCode::InvalidBytecode);
+            diagnostics[0].message.clone()
+        },
+        _ => panic!("the file was accepted"),
+    }
+}
+
+// the same FNV-1a the header holds, so tests can damage a body without tripping the checksum
+fn reseal(bytes: &mut [u8]) {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes[HEADER_SIZE..].iter() {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    bytes[6..10].copy_from_slice(&hash.to_le_bytes());
+}
+
+// where the script's arity, and then its code, start in the body
+// the script has no name, then comes the file name "test.lox"
+fn arity_offset() -> usize {
+    HEADER_SIZE + 1 + 4 + "test.lox".len()
+}
+
+fn code_offset() -> usize {
+    // arity, upvalue count, code length
+    arity_offset() + 4 + 4 + 4
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).expect("tests/lox is readable") {
+        let path = entry.expect("tests/lox is readable").path();
+        if path.is_dir() {
+            collect_scripts(&path, scripts);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            scripts.push(path);
+        }
+    }
+}
+
+#[test]
+fn every_test_script_runs_the_same_from_bytecode() {
+    let mut scripts = Vec::new();
+    collect_scripts(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("lox"), &mut scripts);
+
+    for script in scripts {
+        let source = fs::read_to_string(&script).expect("test scripts are utf-8");
+        let (mut vm, from_source) = new_vm();
+        let bytes = match vm.compile_to_bytecode(&source, "test.lox") {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        let expected = vm.interpret_named(&source, "test.lox");
+
+        let (mut vm, from_bytecode) = new_vm();
+        let result = vm.interpret_bytecode(&bytes);
+        assert_eq!(from_bytecode.contents(), from_source.contents(), "{}", script.display());
+        match (expected, result) {
+            (InterpretResult::Ok, InterpretResult::Ok) => (),
+            (InterpretResult::RuntimeError(expected), InterpretResult::RuntimeError(actual)) => {
+                assert_eq!(actual, expected, "{}", script.display())
+            },
+            (_, InterpretResult::CompileError(diagnostics)) => panic!("{} was refused: {:?}", script.display(), diagnostics),
+            _ => panic!("{} ended differently from bytecode", script.display()),
+        }
+    }
+}
+
+#[test]
+fn other_versions_are_refused() {
+    let mut bytes = compile("print 1;");
+    bytes[4..6].copy_from_slice(&(FORMAT_VERSION - 1).to_le_bytes());
+    assert!(rejection(&bytes).contains("Recompile the script"));
+}
+
+#[test]
+fn damaged_files_are_refused() {
+    let mut bytes = compile("print 1;");
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    assert_eq!(rejection(&bytes), "Compiled file is corrupt (checksum mismatch).");
+
+    let bytes = compile("print 1;");
+    assert_eq!(rejection(&bytes[..bytes.len() - 3]), "Compiled file is corrupt (checksum mismatch).");
+}
+
+#[test]
+fn a_script_taking_arguments_is_refused() {
+    let mut bytes = compile("print 1;");
+    bytes[arity_offset()] = 1;
+    reseal(&mut bytes);
+    assert_eq!(rejection(&bytes), "Compiled file is malformed, its script takes arguments.");
+}
+
+#[test]
+fn unknown_opcodes_are_refused() {
+    let mut bytes = compile("print 1;");
+    bytes[code_offset()] = 0xff;
+    reseal(&mut bytes);
+    assert_eq!(rejection(&bytes), "Compiled file is malformed, unknown opcode 255 at offset 0.");
+}
+
+#[test]
+fn missing_constants_are_refused() {
+    // OP_CONSTANT 0, OP_PRINT, ...
+    let mut bytes = compile("print 1;");
+    bytes[code_offset() + 1] = 200;
+    reseal(&mut bytes);
+    assert_eq!(rejection(&bytes), "Compiled file is malformed, the instruction at offset 0 uses constant 200 of 1.");
+}
+
+#[test]
+fn jumps_into_an_instruction_are_refused() {
+    // 0 OP_TRUE, 1 OP_JUMP_IF_FALSE, 4 OP_POP, 5 OP_CONSTANT 0, 7 OP_PRINT, ...
+    // aim the jump at the constant's operand
+    let mut bytes = compile("if (true) print 1;");
+    let code = code_offset();
+    assert_eq!(bytes[code + 1..code + 6], [10, 7, 0, 36, 0]);
+    bytes[code + 2] = 2;
+    reseal(&mut bytes);
+    assert_eq!(rejection(&bytes), "Compiled file is malformed, the jump at offset 1 doesn't land on an instruction.");
+}
+
+#[test]
+fn local_slots_past_the_top_are_refused() {
+    // OP_CONSTANT 0, OP_PRINT, ...
+    // only the script itself is in the frame, so slot 5 is past the top
+    let mut bytes = compile("print 1;");
+    let code = code_offset();
+    bytes[code] = 9;
+    bytes[code + 1] = 5;
+    reseal(&mut bytes);
+    assert_eq!(rejection(&bytes), "Compiled file is malformed, the instruction at offset 0 uses more of the stack than it holds.");
+}
+
+#[test]
+fn popping_an_empty_stack_is_refused() {
+    // OP_CONSTANT 0, OP_PRINT, ...
+    // the script and the constant aren't enough for OP_SET_INDEX
+    let mut bytes = compile("print 1;");
+    let code = code_offset();
+    assert_eq!(bytes[code + 2], 45);
+    bytes[code + 2] = 52;
+    reseal(&mut bytes);
+    assert_eq!(rejection(&bytes), "Compiled file is malformed, the instruction at offset 2 uses more of the stack than it holds.");
+}
+
+#[test]
+fn paths_disagreeing_on_stack_depth_are_refused() {
+    // 0 OP_TRUE, 1 OP_JUMP_IF_FALSE 7, 4 OP_POP, 5 OP_CONSTANT 0, 7 OP_PRINT, 8 OP_JUMP 1, 11 OP_POP, 12 OP_NIL, ...
+    // pushing instead of popping on the else path leaves an extra value at the join
+    let mut bytes = compile("if (true) print 1;");
+    let code = code_offset();
+    assert_eq!(bytes[code + 8..code + 13], [11, 1, 0, 36, 37]);
+    bytes[code + 11] = 37;
+    reseal(&mut bytes);
+    assert_eq!(rejection(&bytes), "Compiled file is malformed, the paths reaching offset 12 leave different amounts on the stack.");
+}
@@ -0,0 +1,112 @@
+// how diagnostics render, with the offending line and the span underlined
+
+use lox_rust::{InterpretResult, VM};
+
+fn rendered(source: &str) -> String {
+    let mut vm = VM::new();
+    match vm.interpret(source) {
+        InterpretResult::CompileError(diagnostics) => diagnostics[0].render(source),
+        InterpretResult::RuntimeError(diagnostic) => diagnostic.render(source),
+        InterpretResult::Ok => panic!("expected the script to fail"),
+    }
+}
+
+#[test]
+fn underlines_the_offending_token() {
+    assert_eq!(rendered("var x = ;"), "\
+[line 1:9] Error[E0002] at ';': Expect expression.
+  |
+1 | var x = ;
+  |         ^");
+
+    assert_eq!(rendered("var answer = 4 2;"), "\
+[line 1:16] Error[E0002] at '2': Expect ';' after variable declaration
+  |
+1 | var answer = 4 2;
+  |                ^");
+}
+
+#[test]
+fn counts_characters_not_bytes() {
+    assert_eq!(rendered("var é = \"ü\" + 1;"), "\
+[line 1:13] Error[E0100] at '+': Operands must be both numbers or strings
+  |
+1 | var é = \"ü\" + 1;
+  |             ^
+    at script (<script>:1)");
+}
+
+#[test]
+fn wide_characters_take_two_columns() {
+    assert_eq!(rendered("var 名前 = nil; print 名前 + 1;"), "\
+[line 1:24] Error[E0100] at '+': Operands must be both numbers or strings
+  |
+1 | var 名前 = nil; print 名前 + 1;
+  |                            ^
+    at script (<script>:1)");
+
+    assert_eq!(rendered("print 1 \"名前\";"), "\
+[line 1:9] Error[E0002] at '\"名前\"': Expect ';' after value.
+  |
+1 | print 1 \"名前\";
+  |         ^^^^^^");
+}
+
+#[test]
+fn combining_marks_take_no_columns() {
+    // e followed by U+0301 COMBINING ACUTE ACCENT
+    assert_eq!(rendered("var cafe\u{301} = ;"), "\
+[line 1:13] Error[E0002] at ';': Expect expression.
+  |
+1 | var cafe\u{301} = ;
+  |            ^");
+}
+
+#[test]
+fn keeps_tabs_so_carets_line_up() {
+    assert_eq!(rendered("\t\tprint  1 +\t;"), "\
+[line 1:14] Error[E0002] at ';': Expect expression.
+  |
+1 | \t\tprint  1 +\t;
+  | \t\t          \t^");
+}
+
+#[test]
+fn spans_over_several_lines_are_underlined_to_the_end_of_the_first() {
+    assert_eq!(rendered("print 1 \"one\ntwo\";"), "\
+[line 1:9] Error[E0002] at '\"one\ntwo\"': Expect ';' after value.
+  |
+1 | print 1 \"one
+  |         ^^^^");
+}
+
+#[test]
+fn errors_at_the_end_of_the_file() {
+    assert_eq!(rendered("print 1"), "\
+[line 1:8] Error[E0002] at end: Expect ';' after value.
+  |
+1 | print 1
+  |        ^");
+
+    // the end is on the empty line after the last newline
+    assert_eq!(rendered("{\n  print 1;\n"), "\
+[line 3:1] Error[E0002] at end: Expect '}'  after block.
+  |
+3 | 
+  | ^");
+}
+
+#[test]
+fn runtime_errors_carry_the_call_stack() {
+    let source = "fun inner() {\n  return nil + 1;\n}\nfun outer() {\n  inner();\n}\nouter();\n";
+    let mut vm = VM::new();
+    let diagnostic = match vm.interpret_named(source, "main.lox") {
+        InterpretResult::RuntimeError(diagnostic) => diagnostic,
+        _ => panic!("expected the script to fail"),
+    };
+    assert_eq!(diagnostic.to_string(), "\
+[line 2:14] Error[E0100]: Operands must be both numbers or strings
+    at inner() (main.lox:2)
+    at outer() (main.lox:5)
+    at script (main.lox:7)");
+}
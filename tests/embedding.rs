@@ -0,0 +1,156 @@
+// the library api as a host program sees it, for what the .lox scripts can't reach
+
+use std::cell::{Cell, RefCell};
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use lox_rust::{InterpretResult, Root, SharedBuffer, Value, VM};
+
+fn vm_with_gc_log() -> (VM, SharedBuffer) {
+    let log = SharedBuffer::new();
+    let mut vm = VM::with_streams(Box::new(SharedBuffer::new()), Box::new(log.clone()), Box::new(std::io::empty()));
+    vm.set_gc_log(true);
+    (vm, log)
+}
+
+fn vm_with_output() -> (VM, SharedBuffer) {
+    let output = SharedBuffer::new();
+    let vm = VM::with_streams(Box::new(output.clone()), Box::new(SharedBuffer::new()), Box::new(std::io::empty()));
+    (vm, output)
+}
+
+#[test]
+fn natives_can_capture_host_state() {
+    let (mut vm, output) = vm_with_output();
+
+    let calls = Rc::new(Cell::new(0));
+    let counter = calls.clone();
+    vm.define_native("tick", 0, move |_vm, _args| {
+        counter.set(counter.get() + 1);
+        Ok(Value::from(counter.get() as i64))
+    });
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let lines = log.clone();
+    vm.define_native("log", 1, move |vm, args| {
+        lines.borrow_mut().push(vm.format_value(args[0]));
+        Ok(Value::nil_val())
+    });
+
+    let result = vm.interpret("tick(); tick(); log(\"hi\"); print tick();");
+    assert!(matches!(result, InterpretResult::Ok));
+    assert_eq!(calls.get(), 3);
+    assert_eq!(*log.borrow(), vec!["\"hi\"".to_string()]);
+    assert_eq!(output.contents(), "3\n");
+}
+
+#[test]
+fn growing_lists_trigger_collections() {
+    // pushing numbers allocates nothing new, only the lists' own storage grows
+    let (mut vm, log) = vm_with_gc_log();
+    let result = vm.interpret("
+        for (var round = 0; round < 20; round = round + 1) {
+            var items = [];
+            for (var i = 0; i < 20000; i = i + 1) push(items, i);
+        }
+    ");
+    assert!(matches!(result, InterpretResult::Ok));
+    assert!(log.contents().contains("-- gc begin"), "no collections ran");
+}
+
+#[test]
+fn growing_maps_trigger_collections() {
+    let (mut vm, log) = vm_with_gc_log();
+    let result = vm.interpret("
+        for (var round = 0; round < 20; round = round + 1) {
+            var entries = {};
+            for (var i = 0; i < 20000; i = i + 1) entries[i] = i;
+        }
+    ");
+    assert!(matches!(result, InterpretResult::Ok));
+    assert!(log.contents().contains("-- gc begin"), "no collections ran");
+}
+
+#[test]
+fn large_constant_pools_compile() {
+    // 40k distinct numbers plus one identifier used 40k times
+    let mut source = String::from("var total = 0;\n");
+    for n in 0..40_000 {
+        source.push_str(&format!("total = total + {};\n", n));
+    }
+    source.push_str("print total;\n");
+
+    let (mut vm, output) = vm_with_output();
+    let result = vm.interpret(&source);
+    assert!(matches!(result, InterpretResult::Ok));
+    assert_eq!(output.contents(), "799980000\n");
+}
+
+#[test]
+fn natives_read_arguments_as_rust_values() {
+    let (mut vm, output) = vm_with_output();
+
+    vm.define_native("describe", 4, |vm, args| {
+        let flag = bool::try_from(args[0])?;
+        let ratio = f64::try_from(args[1])?;
+        let count = i64::try_from(args[2])?;
+        let name = vm.as_str(args[3]).ok_or("Expected a string.")?.to_string();
+        let text = format!("{} {} {} {}", flag, ratio, count, name);
+        Ok(vm.new_string(&text))
+    });
+
+    let result = vm.interpret("print describe(true, 3, 4.0, \"lox\");");
+    assert!(matches!(result, InterpretResult::Ok));
+    assert_eq!(output.contents(), "\"true 3 4 lox\"\n");
+
+    let result = vm.interpret("describe(nil, 1, 2, \"x\");");
+    assert!(matches!(result, InterpretResult::RuntimeError(_)));
+}
+
+#[test]
+fn values_convert_back_to_rust_types() {
+    assert_eq!(bool::try_from(Value::from(true)), Ok(true));
+    assert_eq!(f64::try_from(Value::from(2.5)), Ok(2.5));
+    assert_eq!(f64::try_from(Value::from(7_i64)), Ok(7.0));
+    assert_eq!(i64::try_from(Value::from(7_i64)), Ok(7));
+    assert_eq!(i64::try_from(Value::from(8.0)), Ok(8));
+
+    assert_eq!(bool::try_from(Value::nil_val()), Err("Expected a bool.".to_string()));
+    assert_eq!(f64::try_from(Value::from(false)), Err("Expected a number.".to_string()));
+    assert_eq!(i64::try_from(Value::from(8.5)), Err("Expected an integer.".to_string()));
+}
+
+#[test]
+fn rooted_values_outlive_the_native_that_made_them() {
+    // object values are only valid inside one native call, keeping one longer takes a Root
+    let (mut vm, output) = vm_with_output();
+    vm.set_gc_stress(true);
+
+    let kept: Rc<RefCell<Vec<Root>>> = Rc::new(RefCell::new(Vec::new()));
+    let keep = kept.clone();
+    vm.define_native("keep", 1, move |vm, args| {
+        keep.borrow_mut().push(vm.root(args[0]));
+        Ok(Value::nil_val())
+    });
+    let recall = kept.clone();
+    vm.define_native("recall", 1, move |_vm, args| {
+        let index = i64::try_from(args[0])? as usize;
+        Ok(recall.borrow()[index].value())
+    });
+
+    let greeting = vm.new_string("made by the host");
+    kept.borrow_mut().push(vm.root(greeting));
+
+    let result = vm.interpret("keep([1, \"two\", {\"three\": 3}]);");
+    assert!(matches!(result, InterpretResult::Ok));
+
+    // every instruction collects, and the churn reuses any slot that was wrongly freed
+    let result = vm.interpret("
+        var churn = [];
+        for (var i = 0; i < 50; i = i + 1) push(churn, [\"item ${i}\"]);
+        print recall(0);
+        print recall(1);
+    ");
+    assert!(matches!(result, InterpretResult::Ok));
+    assert_eq!(output.contents(), "\"made by the host\"\n[1, \"two\", {\"three\": 3}]\n");
+}
@@ -0,0 +1,170 @@
+// runs every script under tests/lox and checks it against the annotations in its comments,
+// in the style of the Crafting Interpreters test suite
+//
+//   print 1 + 2; // expect: 3
+//     the script prints exactly these lines, in order
+//   var x = ; // Error at ';': Expect expression.
+//     compiling reports this error on the annotated line
+//   // [line 3] Error at end: Expect '}' after block.
+//     same, for errors on a line that can't hold a comment
+//   nil + 1; // expect runtime error: Operands must be both numbers or strings
+//     running stops with this error on the annotated line
+//   // expect trace: at inner() (call/trace.lox:2)
+//     the error's stack trace has exactly these frames, innermost first
+//     scripts are named by their path under tests/lox
+//   // stdin: hello
+//     readLine() returns these lines, in order, then nil
+//
+// every script runs twice, the second time collecting garbage before each instruction
+
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use lox_rust::{InterpretResult, SharedBuffer, VM};
+
+const EXPECT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+const EXPECT_TRACE: &str = "// expect trace: ";
+const STDIN: &str = "// stdin:";
+const ERROR: &str = "// Error";
+const LINE_ERROR: &str = "// [line ";
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    // "[line N] Error at 'x': message", the same shape the runner builds from diagnostics
+    compile_errors: Vec<String>,
+    runtime_error: Option<(i32, String)>,
+    trace: Vec<String>,
+    input: Vec<String>,
+}
+
+fn parse_expectations(source: &str) -> Expectations {
+    let mut expected = Expectations::default();
+    for (index, text) in source.lines().enumerate() {
+        let line = index as i32 + 1;
+        if let Some(at) = text.find(EXPECT) {
+            expected.output.push(text[at + EXPECT.len()..].to_string());
+        } else if let Some(at) = text.find(EXPECT_TRACE) {
+            expected.trace.push(text[at + EXPECT_TRACE.len()..].to_string());
+        } else if let Some(at) = text.find(STDIN) {
+            // a bare `// stdin:` is an empty line
+            let line = &text[at + STDIN.len()..];
+            expected.input.push(line.strip_prefix(' ').unwrap_or(line).to_string());
+        } else if let Some(at) = text.find(EXPECT_RUNTIME_ERROR) {
+            expected.runtime_error = Some((line, text[at + EXPECT_RUNTIME_ERROR.len()..].to_string()));
+        } else if let Some(at) = text.find(LINE_ERROR) {
+            expected.compile_errors.push(text[at + 3..].to_string());
+        } else if let Some(at) = text.find(ERROR) {
+            expected.compile_errors.push(format!("[line {}] {}", line, &text[at + 3..]));
+        }
+    }
+    expected
+}
+
+// what went wrong with one script, or None if it behaved as annotated
+fn run_script(root: &Path, path: &Path, stress_gc: bool) -> Option<String> {
+    let source = fs::read_to_string(path).expect("test scripts are utf-8");
+    let expected = parse_expectations(&source);
+
+    let output = SharedBuffer::new();
+    let errors = SharedBuffer::new();
+    let input: String = expected.input.iter().map(|line| format!("{}\n", line)).collect();
+    let mut vm = VM::with_streams(Box::new(output.clone()), Box::new(errors), Box::new(Cursor::new(input)));
+    vm.set_gc_stress(stress_gc);
+    let name: Vec<String> = path.strip_prefix(root).expect("scripts are under the root")
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let result = vm.interpret_named(&source, &name.join("/"));
+
+    let mut problems = Vec::new();
+    match result {
+        InterpretResult::Ok => {
+            if !expected.compile_errors.is_empty() {
+                problems.push("expected compile errors, but the script compiled".to_string());
+            }
+            if let Some((line, message)) = &expected.runtime_error {
+                problems.push(format!("expected runtime error on line {}: {}, but the script finished", line, message));
+            }
+        },
+        InterpretResult::CompileError(diagnostics) => {
+            let actual: Vec<String> = diagnostics.iter()
+                .map(|diagnostic| format!(
+                    "[line {}] Error{}: {}",
+                    diagnostic.line.unwrap_or(0),
+                    diagnostic.location_in(&source),
+                    diagnostic.message
+                ))
+                .collect();
+            if actual != expected.compile_errors {
+                problems.push(format!("expected compile errors\n    {}\ngot\n    {}", expected.compile_errors.join("\n    "), actual.join("\n    ")));
+            }
+        },
+        InterpretResult::RuntimeError(diagnostic) => {
+            let actual = (diagnostic.line.unwrap_or(0), diagnostic.message.clone());
+            match &expected.runtime_error {
+                Some(expected) if *expected == actual => (),
+                Some((line, message)) => problems.push(format!(
+                    "expected runtime error on line {}: {}\ngot runtime error on line {}: {}",
+                    line, message, actual.0, actual.1
+                )),
+                None => problems.push(format!("unexpected runtime error on line {}: {}", actual.0, actual.1)),
+            }
+
+            let trace: Vec<String> = diagnostic.trace.iter().map(|frame| frame.to_string()).collect();
+            if !expected.trace.is_empty() && trace != expected.trace {
+                problems.push(format!("expected trace\n    {}\ngot\n    {}", expected.trace.join("\n    "), trace.join("\n    ")));
+            }
+        },
+    }
+
+    let printed = output.contents();
+    let printed: Vec<&str> = printed.lines().collect();
+    if printed != expected.output {
+        problems.push(format!("expected output\n    {}\ngot\n    {}", expected.output.join("\n    "), printed.join("\n    ")));
+    }
+
+    if problems.is_empty() {
+        None
+    } else {
+        let mode = if stress_gc { " (gc stress)" } else { "" };
+        Some(format!("{}{}:\n  {}", path.display(), mode, problems.join("\n  ")))
+    }
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("tests/lox is readable")
+        .map(|entry| entry.expect("tests/lox is readable").path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_scripts(&path, scripts);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            scripts.push(path);
+        }
+    }
+}
+
+#[test]
+fn lox_scripts() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("lox");
+    let mut scripts = Vec::new();
+    collect_scripts(&root, &mut scripts);
+    assert!(!scripts.is_empty(), "no scripts found under {}", root.display());
+
+    let mut failures = Vec::new();
+    for script in scripts.iter() {
+        for stress_gc in [false, true] {
+            if let Some(failure) = run_script(&root, script, stress_gc) {
+                failures.push(failure);
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{} of {} scripts failed\n\n{}", failures.len(), scripts.len(), failures.join("\n\n"));
+}